@@ -0,0 +1,330 @@
+use crate::{operator::NUM_OPERATORS, MAX_BLOCK_SIZE};
+use nih_plug::prelude::*;
+
+/// The time it takes to crossfade between two algorithms when the algorithm is changed while notes
+/// are playing.
+const ROUTING_SMOOTHING_MS: f32 = 20.0;
+
+/// The 32 operator topologies from the DX7. Operators are numbered from 1 to 6 here to match the
+/// DX7's documentation, while the rest of the plugin uses zero based indices.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Algorithm {
+    #[name = "1"]
+    A1,
+    #[name = "2"]
+    A2,
+    #[name = "3"]
+    A3,
+    #[name = "4"]
+    A4,
+    #[name = "5"]
+    A5,
+    #[name = "6"]
+    A6,
+    #[name = "7"]
+    A7,
+    #[name = "8"]
+    A8,
+    #[name = "9"]
+    A9,
+    #[name = "10"]
+    A10,
+    #[name = "11"]
+    A11,
+    #[name = "12"]
+    A12,
+    #[name = "13"]
+    A13,
+    #[name = "14"]
+    A14,
+    #[name = "15"]
+    A15,
+    #[name = "16"]
+    A16,
+    #[name = "17"]
+    A17,
+    #[name = "18"]
+    A18,
+    #[name = "19"]
+    A19,
+    #[name = "20"]
+    A20,
+    #[name = "21"]
+    A21,
+    #[name = "22"]
+    A22,
+    #[name = "23"]
+    A23,
+    #[name = "24"]
+    A24,
+    #[name = "25"]
+    A25,
+    #[name = "26"]
+    A26,
+    #[name = "27"]
+    A27,
+    #[name = "28"]
+    A28,
+    #[name = "29"]
+    A29,
+    #[name = "30"]
+    A30,
+    #[name = "31"]
+    A31,
+    #[name = "32"]
+    A32,
+}
+
+/// Describes which operators modulate which, and which operators are carriers that end up in the
+/// voice's output.
+#[derive(Debug, Clone, Copy)]
+pub struct Routing {
+    /// For every operator, a bitmask of the operators modulating it. Bit `n` refers to the
+    /// operator at index `n`.
+    modulators: [u8; NUM_OPERATORS],
+    /// A bitmask of the operators that are summed into the voice's output.
+    carriers: u8,
+    /// The index of the operator that has feedback on the DX7. In algorithms 4 and 6 the DX7 feeds
+    /// back through a chain of operators, which is approximated by feedback on the last operator.
+    feedback_operator: usize,
+}
+
+/// The routings for every [`Algorithm`], in the same order. Connections are `(modulator, target)`
+/// pairs using the DX7's one based operator numbers, followed by the carriers and the operator with
+/// feedback. In all of these, modulators have a higher number than the operators they modulate.
+const ROUTINGS: [Routing; 32] = [
+    Routing::new(&[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3], 6),
+    Routing::new(&[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3], 2),
+    Routing::new(&[(2, 1), (3, 2), (5, 4), (6, 5)], &[1, 4], 6),
+    Routing::new(&[(2, 1), (3, 2), (5, 4), (6, 5)], &[1, 4], 6),
+    Routing::new(&[(2, 1), (4, 3), (6, 5)], &[1, 3, 5], 6),
+    Routing::new(&[(2, 1), (4, 3), (6, 5)], &[1, 3, 5], 6),
+    Routing::new(&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], 6),
+    Routing::new(&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], 4),
+    Routing::new(&[(2, 1), (4, 3), (5, 3), (6, 5)], &[1, 3], 2),
+    Routing::new(&[(2, 1), (3, 2), (5, 4), (6, 4)], &[1, 4], 3),
+    Routing::new(&[(2, 1), (3, 2), (5, 4), (6, 4)], &[1, 4], 6),
+    Routing::new(&[(2, 1), (4, 3), (5, 3), (6, 3)], &[1, 3], 2),
+    Routing::new(&[(2, 1), (4, 3), (5, 3), (6, 3)], &[1, 3], 6),
+    Routing::new(&[(2, 1), (4, 3), (5, 4), (6, 4)], &[1, 3], 6),
+    Routing::new(&[(2, 1), (4, 3), (5, 4), (6, 4)], &[1, 3], 2),
+    Routing::new(&[(2, 1), (3, 1), (4, 3), (5, 1), (6, 5)], &[1], 6),
+    Routing::new(&[(2, 1), (3, 1), (4, 3), (5, 1), (6, 5)], &[1], 2),
+    Routing::new(&[(2, 1), (3, 1), (4, 1), (5, 4), (6, 5)], &[1], 3),
+    Routing::new(&[(2, 1), (3, 2), (6, 4), (6, 5)], &[1, 4, 5], 6),
+    Routing::new(&[(3, 1), (3, 2), (5, 4), (6, 4)], &[1, 2, 4], 3),
+    Routing::new(&[(3, 1), (3, 2), (6, 4), (6, 5)], &[1, 2, 4, 5], 3),
+    Routing::new(&[(2, 1), (6, 3), (6, 4), (6, 5)], &[1, 3, 4, 5], 6),
+    Routing::new(&[(3, 2), (6, 4), (6, 5)], &[1, 2, 4, 5], 6),
+    Routing::new(&[(6, 3), (6, 4), (6, 5)], &[1, 2, 3, 4, 5], 6),
+    Routing::new(&[(6, 4), (6, 5)], &[1, 2, 3, 4, 5], 6),
+    Routing::new(&[(3, 2), (5, 4), (6, 4)], &[1, 2, 4], 6),
+    Routing::new(&[(3, 2), (5, 4), (6, 4)], &[1, 2, 4], 3),
+    Routing::new(&[(2, 1), (4, 3), (5, 4)], &[1, 3, 6], 5),
+    Routing::new(&[(4, 3), (6, 5)], &[1, 2, 3, 5], 6),
+    Routing::new(&[(4, 3), (5, 4)], &[1, 2, 3, 6], 5),
+    Routing::new(&[(6, 5)], &[1, 2, 3, 4, 5], 6),
+    Routing::new(&[], &[1, 2, 3, 4, 5, 6], 6),
+];
+
+impl Algorithm {
+    /// Get the operator routing for this algorithm.
+    pub fn routing(self) -> &'static Routing {
+        &ROUTINGS[self as usize]
+    }
+}
+
+impl Routing {
+    const fn new(connections: &[(usize, usize)], carriers: &[usize], feedback: usize) -> Self {
+        let mut routing = Self {
+            modulators: [0; NUM_OPERATORS],
+            carriers: 0,
+            feedback_operator: feedback - 1,
+        };
+
+        let mut i = 0;
+        while i < connections.len() {
+            let (modulator, target) = connections[i];
+            routing.modulators[target - 1] |= 1 << (modulator - 1);
+            i += 1;
+        }
+
+        let mut i = 0;
+        while i < carriers.len() {
+            routing.carriers |= 1 << (carriers[i] - 1);
+            i += 1;
+        }
+
+        routing
+    }
+
+    /// Whether the operator at `modulator_idx` modulates the operator at `target_idx`.
+    pub fn modulates(&self, modulator_idx: usize, target_idx: usize) -> bool {
+        self.modulators[target_idx] & (1 << modulator_idx) != 0
+    }
+
+    /// The index of the operator the DX7 applies this algorithm's feedback to.
+    pub fn feedback_operator(&self) -> usize {
+        self.feedback_operator
+    }
+
+    /// Whether the operator at `operator_idx` is summed into the voice's output.
+    pub fn is_carrier(&self, operator_idx: usize) -> bool {
+        self.carriers & (1 << operator_idx) != 0
+    }
+}
+
+/// The routing rendered for a single block of audio. Every value is a gain in `[0, 1]`, which
+/// only lies between those values while crossfading between two algorithms.
+pub struct RoutingBlock {
+    /// The modulation depths indexed by `[target_idx][modulator_idx][value_idx]`.
+    pub modulation: [[[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS]; NUM_OPERATORS],
+    /// The gain for every operator's output before it's summed into the voice's output, indexed by
+    /// `[operator_idx][value_idx]`.
+    pub carriers: [[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+}
+
+impl Default for RoutingBlock {
+    fn default() -> Self {
+        Self {
+            modulation: [[[0.0; MAX_BLOCK_SIZE]; NUM_OPERATORS]; NUM_OPERATORS],
+            carriers: [[0.0; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+        }
+    }
+}
+
+/// Smooths every connection in the routing so the algorithm can be changed while notes are playing
+/// without clicks. The smoothers are allocated up front, so switching algorithms doesn't allocate.
+#[derive(Debug)]
+pub struct RoutingSmoother {
+    /// The algorithm the smoothers are currently moving towards. `None` until the first call to
+    /// [`reset()`][Self::reset()] or [`set_algorithm()`][Self::set_algorithm()].
+    algorithm: Option<Algorithm>,
+    modulation: [[Smoother<f32>; NUM_OPERATORS]; NUM_OPERATORS],
+    carriers: [Smoother<f32>; NUM_OPERATORS],
+}
+
+impl Default for RoutingSmoother {
+    fn default() -> Self {
+        let new_smoother = || Smoother::new(SmoothingStyle::Linear(ROUTING_SMOOTHING_MS));
+
+        Self {
+            algorithm: None,
+            modulation: std::array::from_fn(|_| std::array::from_fn(|_| new_smoother())),
+            carriers: std::array::from_fn(|_| new_smoother()),
+        }
+    }
+}
+
+impl RoutingSmoother {
+    /// Immediately switch to `algorithm` without any smoothing.
+    pub fn reset(&mut self, algorithm: Algorithm) {
+        let routing = algorithm.routing();
+        for (target_idx, smoothers) in self.modulation.iter_mut().enumerate() {
+            for (modulator_idx, smoother) in smoothers.iter_mut().enumerate() {
+                smoother.reset(connection_gain(
+                    routing.modulates(modulator_idx, target_idx),
+                ));
+            }
+        }
+        for (operator_idx, smoother) in self.carriers.iter_mut().enumerate() {
+            smoother.reset(connection_gain(routing.is_carrier(operator_idx)));
+        }
+
+        self.algorithm = Some(algorithm);
+    }
+
+    /// Start crossfading to `algorithm`. This does nothing if the algorithm hasn't changed.
+    pub fn set_algorithm(&mut self, sample_rate: f32, algorithm: Algorithm) {
+        if self.algorithm == Some(algorithm) {
+            return;
+        }
+        if self.algorithm.is_none() {
+            self.reset(algorithm);
+            return;
+        }
+
+        let routing = algorithm.routing();
+        for (target_idx, smoothers) in self.modulation.iter_mut().enumerate() {
+            for (modulator_idx, smoother) in smoothers.iter_mut().enumerate() {
+                smoother.set_target(
+                    sample_rate,
+                    connection_gain(routing.modulates(modulator_idx, target_idx)),
+                );
+            }
+        }
+        for (operator_idx, smoother) in self.carriers.iter_mut().enumerate() {
+            smoother.set_target(
+                sample_rate,
+                connection_gain(routing.is_carrier(operator_idx)),
+            );
+        }
+
+        self.algorithm = Some(algorithm);
+    }
+
+    /// Render the smoothed routing for the next `block_len` samples.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_len > MAX_BLOCK_SIZE`.
+    pub fn next_block(&mut self, block: &mut RoutingBlock, block_len: usize) {
+        for (smoothers, values) in self.modulation.iter_mut().zip(block.modulation.iter_mut()) {
+            for (smoother, values) in smoothers.iter_mut().zip(values.iter_mut()) {
+                smoother.next_block(values, block_len);
+            }
+        }
+        for (smoother, values) in self.carriers.iter_mut().zip(block.carriers.iter_mut()) {
+            smoother.next_block(values, block_len);
+        }
+    }
+}
+
+/// The gain for a connection in the routing.
+fn connection_gain(connected: bool) -> f32 {
+    if connected {
+        1.0
+    } else {
+        0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Check that `algorithm` has exactly these connections and carriers, using the DX7's one based
+    /// operator numbers like [`ROUTINGS`].
+    fn assert_routing(algorithm: Algorithm, connections: &[(usize, usize)], carriers: &[usize]) {
+        let routing = algorithm.routing();
+        for target in 1..=NUM_OPERATORS {
+            for modulator in 1..=NUM_OPERATORS {
+                assert_eq!(
+                    routing.modulates(modulator - 1, target - 1),
+                    connections.contains(&(modulator, target)),
+                    "{algorithm:?}: operator {modulator} -> operator {target}"
+                );
+            }
+            assert_eq!(
+                routing.is_carrier(target - 1),
+                carriers.contains(&target),
+                "{algorithm:?}: operator {target} as a carrier"
+            );
+        }
+    }
+
+    #[test]
+    fn algorithm_1_has_two_stacks() {
+        assert_routing(Algorithm::A1, &[(2, 1), (4, 3), (5, 4), (6, 5)], &[1, 3]);
+    }
+
+    #[test]
+    fn algorithm_5_has_three_pairs() {
+        assert_routing(Algorithm::A5, &[(2, 1), (4, 3), (6, 5)], &[1, 3, 5]);
+    }
+
+    #[test]
+    fn algorithm_32_only_has_carriers() {
+        assert_routing(Algorithm::A32, &[], &[1, 2, 3, 4, 5, 6]);
+    }
+}
@@ -0,0 +1,804 @@
+use crate::{
+    algorithm::Algorithm,
+    envelope::{EnvelopeCurve, EnvelopeMode, LoopMode},
+    lfo::{LfoMode, LfoShape},
+    operator::{ScalingCurve, Waveform, NUM_OPERATORS},
+    params::{EnvelopeParams, FmSynthParams},
+};
+use nih_plug::prelude::*;
+use std::{fs, io, path::Path};
+
+/// The number of voices in a bulk dump.
+pub const BANK_SIZE: usize = 32;
+
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+const YAMAHA_ID: u8 = 0x43;
+/// The upper nibble of the sub-status/channel byte for voice data dumps. The lower nibble is the MIDI
+/// channel.
+const SUB_STATUS_DUMP: u8 = 0x00;
+/// The format number for a single voice dump, also called VCED.
+const FORMAT_VOICE: u8 = 0x00;
+/// The format number for a 32 voice bulk dump, also called VMEM.
+const FORMAT_BANK: u8 = 0x09;
+/// The status byte, manufacturer ID, sub-status/channel, format and two byte count.
+const HEADER_LEN: usize = 6;
+/// The size of a voice in a single voice dump.
+const VOICE_LEN: usize = 155;
+/// The size of a voice in a bulk dump, where several parameters are packed into single bytes.
+const PACKED_VOICE_LEN: usize = 128;
+
+/// The DX7's envelope rates are roughly exponential. A full sweep takes this long at rate 0.
+const EG_SLOWEST_SWEEP_MS: f32 = 38_000.0;
+/// The envelope sweep time halves every this many rate steps.
+const EG_RATE_HALVING_STEPS: f32 = 6.5;
+/// Output and envelope levels change the gain by roughly this many decibels per step.
+const LEVEL_STEP_DB: f32 = 0.75;
+/// The DX7's maximum feedback setting of 7 roughly corresponds to this feedback amount.
+const MAX_FEEDBACK: f32 = 0.5;
+/// Pitch envelope levels move the pitch by up to this many semitones in either direction. Level 50
+/// leaves the pitch unchanged.
+const PITCH_EG_RANGE_SEMITONES: f32 = 48.0;
+/// The LFO's frequency at speed 0. The frequency rises roughly exponentially with the speed.
+const LFO_SLOWEST_HZ: f32 = 0.062;
+/// The LFO's frequency at speed 99.
+const LFO_FASTEST_HZ: f32 = 49.2;
+/// At the maximum delay setting the LFO stays silent for about this long, and then takes about as
+/// long again to fade in.
+const LFO_MAX_DELAY_MS: f32 = 2_500.0;
+/// The LFO's pitch depth at the maximum pitch modulation depth and sensitivity.
+const LFO_MAX_PITCH_SEMITONES: f32 = 12.0;
+/// How much of the LFO's pitch modulation depth each pitch modulation sensitivity lets through.
+const LFO_PITCH_SENSITIVITIES: [f32; 8] = [0.0, 0.039, 0.078, 0.129, 0.216, 0.361, 0.6, 1.0];
+/// How much of the LFO's amplitude modulation depth each operator's amplitude modulation
+/// sensitivity lets through.
+const LFO_AMP_SENSITIVITIES: [f32; 4] = [0.0, 0.26, 0.43, 1.0];
+
+/// A DX7 single voice or 32 voice bulk dump. Parsing a bank allocates, so this is only used on the
+/// GUI thread.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Dx7Dump {
+    Voice(Dx7Voice),
+    /// The bank's [`BANK_SIZE`] voices.
+    Bank(Vec<Dx7Voice>),
+}
+
+/// The plugin's SysEx message type. Every note event is as large as the largest SysEx message, so
+/// bulk dumps received over MIDI don't carry their voices. The editor asks for the bank to be
+/// loaded from a `.syx` file instead.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Dx7SysEx {
+    Voice(Dx7Voice),
+    Bank,
+}
+
+/// A single DX7 voice, with all values in the DX7's own ranges.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Dx7Voice {
+    name: [u8; 10],
+    /// The voice's operators, starting at operator 1. SysEx messages store these in reverse.
+    operators: [Dx7Operator; NUM_OPERATORS],
+    /// The algorithm, in `0..32`.
+    algorithm: u8,
+    /// The feedback amount, in `0..8`.
+    feedback: u8,
+    pitch_eg_rates: [u8; 4],
+    /// The pitch envelope's levels, where 50 leaves the pitch unchanged.
+    pitch_eg_levels: [u8; 4],
+    lfo: Dx7Lfo,
+}
+
+/// The DX7's single LFO, which is shared by all voices.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Dx7Lfo {
+    speed: u8,
+    delay: u8,
+    pitch_depth: u8,
+    amp_depth: u8,
+    /// Whether the LFO restarts for new notes.
+    key_sync: bool,
+    /// The waveform, in the order triangle, saw down, saw up, square, sine and sample and hold.
+    wave: u8,
+    /// The pitch modulation sensitivity, in `0..8`.
+    pitch_sensitivity: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+struct Dx7Operator {
+    eg_rates: [u8; 4],
+    eg_levels: [u8; 4],
+    /// The breakpoint, where 0 is A-1 and 39 is C3.
+    breakpoint: u8,
+    left_depth: u8,
+    right_depth: u8,
+    left_curve: u8,
+    right_curve: u8,
+    rate_scaling: u8,
+    /// How much the LFO's amplitude modulation depth affects this operator, in `0..4`.
+    amp_mod_sensitivity: u8,
+    velocity_sensitivity: u8,
+    output_level: u8,
+    fixed: bool,
+    coarse: u8,
+    fine: u8,
+    /// The detune amount, where 7 means no detuning.
+    detune: u8,
+}
+
+impl SysExMessage for Dx7SysEx {
+    // The plugin only ever receives these messages, so they never need to be converted back
+    type Buffer = [u8; 0];
+
+    fn from_buffer(buffer: &[u8]) -> Option<Self> {
+        // This runs on the audio thread, so banks are not parsed
+        match dump_data(buffer)? {
+            (FORMAT_VOICE, data) => Some(Self::Voice(Dx7Voice::from_unpacked(data))),
+            _ => Some(Self::Bank),
+        }
+    }
+
+    fn to_buffer(self) -> (Self::Buffer, usize) {
+        nih_debug_assert_failure!("DX7 SysEx messages cannot be sent");
+        ([], 0)
+    }
+}
+
+impl Dx7Dump {
+    /// Parse a single voice dump or a 32 voice bulk dump. Bulk dumps without a SysEx header are
+    /// also accepted, since some `.syx` files only contain the voice data. Returns `None` if the
+    /// data is not a valid DX7 dump or if the checksum does not match.
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        match dump_data(bytes)? {
+            (FORMAT_VOICE, data) => Some(Self::Voice(Dx7Voice::from_unpacked(data))),
+            (_, data) => Some(Self::Bank(
+                data.chunks_exact(PACKED_VOICE_LEN)
+                    .map(Dx7Voice::from_packed)
+                    .collect(),
+            )),
+        }
+    }
+}
+
+/// Check a dump's header, byte count and checksum, and return its format together with the voice
+/// data without the header and checksum. Only voice and bulk dumps are accepted. Headerless bulk
+/// dumps are returned as [`FORMAT_BANK`].
+fn dump_data(bytes: &[u8]) -> Option<(u8, &[u8])> {
+    if bytes.len() == BANK_SIZE * PACKED_VOICE_LEN {
+        return Some((FORMAT_BANK, bytes));
+    }
+
+    if bytes.len() < HEADER_LEN + 2
+        || bytes[0] != SYSEX_START
+        || bytes[1] != YAMAHA_ID
+        || bytes[2] & 0xf0 != SUB_STATUS_DUMP
+        || bytes[bytes.len() - 1] != SYSEX_END
+    {
+        return None;
+    }
+
+    let format = bytes[3];
+    let byte_count = ((bytes[4] as usize) << 7) | bytes[5] as usize;
+    let data = &bytes[HEADER_LEN..bytes.len() - 2];
+    let checksum = bytes[bytes.len() - 2];
+    let expected_len = match format {
+        FORMAT_VOICE => VOICE_LEN,
+        FORMAT_BANK => BANK_SIZE * PACKED_VOICE_LEN,
+        _ => return None,
+    };
+    if data.len() != expected_len
+        || byte_count != expected_len
+        || checksum != compute_checksum(data)
+    {
+        return None;
+    }
+
+    Some((format, data))
+}
+
+/// Read and parse a `.syx` file containing a DX7 single voice or bulk dump.
+pub fn load_file(path: &Path) -> io::Result<Dx7Dump> {
+    let bytes = fs::read(path)?;
+
+    Dx7Dump::parse(&bytes).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not a DX7 single voice or bulk dump",
+        )
+    })
+}
+
+/// The DX7's checksum is the two's complement of the sum of the data bytes, masked to seven bits.
+fn compute_checksum(data: &[u8]) -> u8 {
+    let sum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+    sum.wrapping_neg() & 0x7f
+}
+
+impl Dx7Voice {
+    /// Parse a voice from a single voice dump. `data` must contain at least [`VOICE_LEN`] bytes.
+    fn from_unpacked(data: &[u8]) -> Self {
+        const OPERATOR_LEN: usize = 21;
+
+        let mut voice = Self::default();
+        for (op_idx, operator) in voice.operators.iter_mut().enumerate() {
+            let data = &data[(NUM_OPERATORS - 1 - op_idx) * OPERATOR_LEN..];
+            *operator = Dx7Operator {
+                eg_rates: [data[0], data[1], data[2], data[3]],
+                eg_levels: [data[4], data[5], data[6], data[7]],
+                breakpoint: data[8],
+                left_depth: data[9],
+                right_depth: data[10],
+                left_curve: data[11] & 0b11,
+                right_curve: data[12] & 0b11,
+                rate_scaling: data[13] & 0b111,
+                amp_mod_sensitivity: data[14] & 0b11,
+                velocity_sensitivity: data[15] & 0b111,
+                output_level: data[16],
+                fixed: data[17] & 1 != 0,
+                coarse: data[18] & 0b11111,
+                fine: data[19],
+                detune: data[20] & 0b1111,
+            };
+        }
+
+        voice.pitch_eg_rates.copy_from_slice(&data[126..130]);
+        voice.pitch_eg_levels.copy_from_slice(&data[130..134]);
+        voice.algorithm = data[134] & 0b11111;
+        voice.feedback = data[135] & 0b111;
+        voice.lfo = Dx7Lfo {
+            speed: data[137],
+            delay: data[138],
+            pitch_depth: data[139],
+            amp_depth: data[140],
+            key_sync: data[141] & 1 != 0,
+            wave: data[142] & 0b111,
+            pitch_sensitivity: data[143] & 0b111,
+        };
+        voice.name.copy_from_slice(&data[145..155]);
+
+        voice
+    }
+
+    /// Parse a voice from a bulk dump. `data` must contain at least [`PACKED_VOICE_LEN`] bytes.
+    fn from_packed(data: &[u8]) -> Self {
+        const OPERATOR_LEN: usize = 17;
+
+        let mut voice = Self::default();
+        for (op_idx, operator) in voice.operators.iter_mut().enumerate() {
+            let data = &data[(NUM_OPERATORS - 1 - op_idx) * OPERATOR_LEN..];
+            *operator = Dx7Operator {
+                eg_rates: [data[0], data[1], data[2], data[3]],
+                eg_levels: [data[4], data[5], data[6], data[7]],
+                breakpoint: data[8],
+                left_depth: data[9],
+                right_depth: data[10],
+                left_curve: data[11] & 0b11,
+                right_curve: (data[11] >> 2) & 0b11,
+                rate_scaling: data[12] & 0b111,
+                amp_mod_sensitivity: data[13] & 0b11,
+                velocity_sensitivity: (data[13] >> 2) & 0b111,
+                output_level: data[14],
+                fixed: data[15] & 1 != 0,
+                coarse: (data[15] >> 1) & 0b11111,
+                fine: data[16],
+                detune: (data[12] >> 3) & 0b1111,
+            };
+        }
+
+        voice.pitch_eg_rates.copy_from_slice(&data[102..106]);
+        voice.pitch_eg_levels.copy_from_slice(&data[106..110]);
+        voice.algorithm = data[110] & 0b11111;
+        voice.feedback = data[111] & 0b111;
+        voice.lfo = Dx7Lfo {
+            speed: data[112],
+            delay: data[113],
+            pitch_depth: data[114],
+            amp_depth: data[115],
+            key_sync: data[116] & 1 != 0,
+            wave: (data[116] >> 1) & 0b111,
+            pitch_sensitivity: (data[116] >> 4) & 0b111,
+        };
+        voice.name.copy_from_slice(&data[118..128]);
+
+        voice
+    }
+
+    /// The voice's name with trailing spaces removed.
+    pub fn name(&self) -> String {
+        String::from_utf8_lossy(&self.name).trim_end().to_owned()
+    }
+
+    /// Set the plugin's parameters to match this voice. The DX7's envelopes map to the rate/level
+    /// envelope mode, and its LFO maps to the first LFO. The modulation matrix is cleared so only
+    /// the algorithm's connections remain.
+    pub fn apply(&self, setter: &ParamSetter, params: &FmSynthParams) {
+        let algorithm = Algorithm::from_index(self.algorithm as usize);
+        set(setter, &params.algorithm, algorithm);
+        self.apply_pitch_envelope(setter, params);
+        self.apply_lfo(setter, params);
+        for slot_params in &params.mod_slots {
+            set(setter, &slot_params.depth, 0.0);
+        }
+
+        let feedback_operator = algorithm.routing().feedback_operator();
+        for (op_idx, (operator, operator_params)) in self
+            .operators
+            .iter()
+            .zip(params.operators.iter())
+            .enumerate()
+        {
+            set(
+                setter,
+                &operator_params.level,
+                level_to_gain(operator.output_level),
+            );
+            set(setter, &operator_params.fixed, operator.fixed);
+            if operator.fixed {
+                // Fixed frequencies are 1, 10, 100 or 1000 Hz multiplied by up to almost 10
+                let frequency = 10.0f32.powi((operator.coarse & 0b11) as i32)
+                    * 10.0f32.powf(operator.fine.min(99) as f32 / 100.0);
+                set(setter, &operator_params.fixed_frequency, frequency);
+            } else {
+                set(setter, &operator_params.coarse, operator.coarse as i32);
+                set(
+                    setter,
+                    &operator_params.fine,
+                    operator.fine.min(99) as f32 / 100.0,
+                );
+            }
+            set(
+                setter,
+                &operator_params.detune_cents,
+                operator.detune as f32 - 7.0,
+            );
+            set(setter, &operator_params.waveform, Waveform::Sine);
+            set(setter, &operator_params.pitch_envelope, true);
+            set(
+                setter,
+                &operator_params.velocity_sensitivity,
+                operator.velocity_sensitivity as f32 / 7.0,
+            );
+            set(
+                setter,
+                &operator_params.feedback,
+                if op_idx == feedback_operator {
+                    self.feedback as f32 / 7.0 * MAX_FEEDBACK
+                } else {
+                    0.0
+                },
+            );
+            for modulation_params in &operator_params.modulation {
+                set(setter, &modulation_params.depth, 0.0);
+            }
+
+            // The DX7's stages change level at an exponential rate
+            set_rate_level_envelope(
+                setter,
+                &operator_params.envelope,
+                operator.eg_rates,
+                operator.eg_levels.map(|level| level_to_gain(level) * 100.0),
+                EnvelopeCurve::Exponential,
+            );
+
+            let scaling = &operator_params.keyboard_scaling;
+            // Breakpoint 0 is A-1, which is MIDI note 21
+            set(
+                setter,
+                &scaling.breakpoint,
+                operator.breakpoint.min(99) as i32 + 21,
+            );
+            set(
+                setter,
+                &scaling.left_depth,
+                operator.left_depth.min(99) as f32 / 99.0,
+            );
+            set(
+                setter,
+                &scaling.right_depth,
+                operator.right_depth.min(99) as f32 / 99.0,
+            );
+            set(
+                setter,
+                &scaling.left_curve,
+                ScalingCurve::from_index(operator.left_curve as usize),
+            );
+            set(
+                setter,
+                &scaling.right_curve,
+                ScalingCurve::from_index(operator.right_curve as usize),
+            );
+            set(
+                setter,
+                &scaling.rate_scaling,
+                operator.rate_scaling as f32 / 7.0,
+            );
+        }
+    }
+
+    /// The DX7's pitch envelope applies to all operators and moves the pitch both up and down from
+    /// level 50. The plugin's pitch envelope instead scales its levels by a single depth, so this
+    /// uses the level furthest from 50 for the depth. Levels on the other side of 50 leave the pitch
+    /// unchanged.
+    fn apply_pitch_envelope(&self, setter: &ParamSetter, params: &FmSynthParams) {
+        let semitones = self
+            .pitch_eg_levels
+            .map(|level| (level.min(99) as f32 - 50.0) / 50.0 * PITCH_EG_RANGE_SEMITONES);
+        let depth = semitones.into_iter().fold(0.0f32, |depth, semitones| {
+            if semitones.abs() > depth.abs() {
+                semitones
+            } else {
+                depth
+            }
+        });
+
+        set(setter, &params.pitch_envelope.depth, depth);
+        // The pitch envelope's levels are in semitones, so they change at a linear rate
+        set_rate_level_envelope(
+            setter,
+            &params.pitch_envelope.envelope,
+            self.pitch_eg_rates,
+            semitones.map(|semitones| {
+                if depth == 0.0 {
+                    0.0
+                } else {
+                    (semitones / depth).max(0.0) * 100.0
+                }
+            }),
+            EnvelopeCurve::Linear,
+        );
+    }
+
+    /// The DX7's LFO maps to the first LFO. Its pitch depth applies to all operators, and its
+    /// amplitude depth is scaled by every operator's amplitude modulation sensitivity. The other
+    /// LFOs are disabled.
+    fn apply_lfo(&self, setter: &ParamSetter, params: &FmSynthParams) {
+        let lfo = &self.lfo;
+        let lfo_params = &params.lfos[0];
+
+        // There is no falling saw, so for pitch the rising saw is turned around with a negative
+        // depth. Operator levels still follow the rising saw.
+        let (shape, pitch_sign) = match lfo.wave {
+            0 => (LfoShape::Triangle, 1.0),
+            1 => (LfoShape::Saw, -1.0),
+            2 => (LfoShape::Saw, 1.0),
+            3 => (LfoShape::Square, 1.0),
+            4 => (LfoShape::Sine, 1.0),
+            _ => (LfoShape::SampleAndHold, 1.0),
+        };
+        let speed = lfo.speed.min(99) as f32 / 99.0;
+        let delay_ms = lfo.delay.min(99) as f32 / 99.0 * LFO_MAX_DELAY_MS;
+        let pitch_depth = lfo.pitch_depth.min(99) as f32 / 99.0
+            * LFO_PITCH_SENSITIVITIES[lfo.pitch_sensitivity as usize]
+            * LFO_MAX_PITCH_SEMITONES;
+        let amp_depth = lfo.amp_depth.min(99) as f32 / 99.0;
+
+        set(setter, &lfo_params.shape, shape);
+        set(setter, &lfo_params.mode, LfoMode::Global);
+        set(setter, &lfo_params.sync, false);
+        set(
+            setter,
+            &lfo_params.rate_hz,
+            LFO_SLOWEST_HZ * (LFO_FASTEST_HZ / LFO_SLOWEST_HZ).powf(speed),
+        );
+        set(setter, &lfo_params.delay_ms, delay_ms);
+        set(setter, &lfo_params.fade_ms, delay_ms);
+        set(setter, &lfo_params.key_sync, lfo.key_sync);
+        set(setter, &lfo_params.pitch_depth, pitch_sign * pitch_depth);
+        set(setter, &lfo_params.gain_depth, 0.0);
+        for (operator, level_params) in self.operators.iter().zip(&lfo_params.level_depths) {
+            set(
+                setter,
+                &level_params.depth,
+                amp_depth * LFO_AMP_SENSITIVITIES[operator.amp_mod_sensitivity as usize],
+            );
+        }
+
+        for lfo_params in &params.lfos[1..] {
+            set(setter, &lfo_params.pitch_depth, 0.0);
+            set(setter, &lfo_params.gain_depth, 0.0);
+            for level_params in &lfo_params.level_depths {
+                set(setter, &level_params.depth, 0.0);
+            }
+        }
+    }
+}
+
+/// Set up a rate/level envelope from the DX7's rates and the given level percentages. The DX7's
+/// envelope starts at level 4, rises to level 1, moves through levels 2 and 3 where it sustains, and
+/// then returns to level 4 during the release. This maps directly to the rate/level envelope.
+fn set_rate_level_envelope(
+    setter: &ParamSetter,
+    envelope: &EnvelopeParams,
+    rates: [u8; 4],
+    level_percentages: [f32; 4],
+    curve: EnvelopeCurve,
+) {
+    set(setter, &envelope.mode, EnvelopeMode::RateLevel);
+    for (rate_param, rate) in [
+        &envelope.rate_1_ms,
+        &envelope.rate_2_ms,
+        &envelope.rate_3_ms,
+        &envelope.rate_4_ms,
+    ]
+    .into_iter()
+    .zip(rates)
+    {
+        set(setter, rate_param, eg_sweep_ms(rate));
+    }
+    for (level_param, level_percentage) in [
+        &envelope.level_1_percentage,
+        &envelope.level_2_percentage,
+        &envelope.level_3_percentage,
+        &envelope.level_4_percentage,
+    ]
+    .into_iter()
+    .zip(level_percentages)
+    {
+        set(setter, level_param, level_percentage);
+    }
+    set(setter, &envelope.loop_mode, LoopMode::Off);
+    for tracking in [
+        &envelope.attack_tracking,
+        &envelope.hold_tracking,
+        &envelope.decay_tracking,
+        &envelope.release_tracking,
+    ] {
+        set(setter, &tracking.velocity, 0.0);
+        set(setter, &tracking.key, 0.0);
+    }
+    for curve_param in [
+        &envelope.attack_curve,
+        &envelope.decay_curve,
+        &envelope.release_curve,
+    ] {
+        set(setter, curve_param, curve);
+    }
+}
+
+/// Set a parameter from the GUI thread as a single gesture.
+fn set<P: Param>(setter: &ParamSetter, param: &P, value: P::Plain) {
+    setter.begin_set_parameter(param);
+    setter.set_parameter(param, value);
+    setter.end_set_parameter(param);
+}
+
+/// Convert a DX7 output or envelope level in `0..100` to a linear gain.
+fn level_to_gain(level: u8) -> f32 {
+    match level.min(99) {
+        0 => 0.0,
+        level => util::db_to_gain((level as f32 - 99.0) * LEVEL_STEP_DB),
+    }
+}
+
+/// How long the DX7's envelope takes to sweep across the full level range at the given rate.
+fn eg_sweep_ms(rate: u8) -> f32 {
+    EG_SLOWEST_SWEEP_MS * 2.0f32.powf(-(rate.min(99) as f32) / EG_RATE_HALVING_STEPS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The packed operator from the DX7's initial voice, with the given output level.
+    fn init_operator(output_level: u8) -> [u8; 17] {
+        [
+            99,
+            99,
+            99,
+            99,
+            99,
+            99,
+            99,
+            0,
+            39,
+            0,
+            0,
+            0,
+            7 << 3,
+            0,
+            output_level,
+            1 << 1,
+            0,
+        ]
+    }
+
+    /// The DX7's initial voice as stored in a bulk dump. Only operator 1 is audible.
+    fn init_voice() -> [u8; PACKED_VOICE_LEN] {
+        let mut data = [0; PACKED_VOICE_LEN];
+        for (op_idx, operator) in data.chunks_exact_mut(17).take(NUM_OPERATORS).enumerate() {
+            // The operators are stored in reverse, so operator 1 comes last
+            let output_level = if op_idx == NUM_OPERATORS - 1 { 99 } else { 0 };
+            operator.copy_from_slice(&init_operator(output_level));
+        }
+        data[102..110].copy_from_slice(&[99, 99, 99, 99, 50, 50, 50, 50]);
+        data[110] = 0;
+        data[111] = 1 << 3;
+        data[112..118].copy_from_slice(&[35, 0, 0, 0, 1 | (3 << 4), 24]);
+        data[118..128].copy_from_slice(b"INIT VOICE");
+
+        data
+    }
+
+    /// A bulk dump of initial voices, where voice `n` uses algorithm `n` and feedback `n % 8`.
+    fn bank_dump() -> Vec<u8> {
+        let mut data = Vec::new();
+        for voice_idx in 0..BANK_SIZE {
+            let mut voice = init_voice();
+            voice[110] = voice_idx as u8;
+            voice[111] |= voice_idx as u8 % 8;
+            data.extend(voice);
+        }
+
+        let mut bytes = vec![SYSEX_START, YAMAHA_ID, 0x00, FORMAT_BANK, 0x20, 0x00];
+        bytes.extend(&data);
+        bytes.extend([compute_checksum(&data), SYSEX_END]);
+
+        bytes
+    }
+
+    #[test]
+    fn checksum_makes_the_sum_a_multiple_of_128() {
+        assert_eq!(compute_checksum(&[]), 0);
+        assert_eq!(compute_checksum(&[1, 2, 3]), 122);
+        assert_eq!(compute_checksum(&[0x7f; 3]), 3);
+
+        let data = &bank_dump()[HEADER_LEN..HEADER_LEN + BANK_SIZE * PACKED_VOICE_LEN];
+        let sum: u32 = data.iter().map(|byte| *byte as u32).sum();
+        assert_eq!((sum + compute_checksum(data) as u32) % 128, 0);
+    }
+
+    #[test]
+    fn parses_bank_dumps() {
+        let bytes = bank_dump();
+        let Some(Dx7Dump::Bank(voices)) = Dx7Dump::parse(&bytes) else {
+            panic!("The bank dump did not parse");
+        };
+        assert_eq!(voices.len(), BANK_SIZE);
+
+        for (voice_idx, voice) in voices.iter().enumerate() {
+            assert_eq!(voice.name(), "INIT VOICE");
+            assert_eq!(voice.algorithm, voice_idx as u8);
+            assert_eq!(voice.feedback, voice_idx as u8 % 8);
+            assert_eq!(voice.pitch_eg_rates, [99; 4]);
+            assert_eq!(voice.pitch_eg_levels, [50; 4]);
+            assert_eq!(
+                voice.lfo,
+                Dx7Lfo {
+                    speed: 35,
+                    delay: 0,
+                    pitch_depth: 0,
+                    amp_depth: 0,
+                    key_sync: true,
+                    wave: 0,
+                    pitch_sensitivity: 3,
+                }
+            );
+
+            for (op_idx, operator) in voice.operators.iter().enumerate() {
+                assert_eq!(operator.eg_rates, [99; 4]);
+                assert_eq!(operator.eg_levels, [99, 99, 99, 0]);
+                assert_eq!(operator.breakpoint, 39);
+                assert_eq!(operator.detune, 7);
+                assert_eq!(operator.coarse, 1);
+                assert!(!operator.fixed);
+                assert_eq!(operator.output_level, if op_idx == 0 { 99 } else { 0 });
+            }
+        }
+
+        // Some `.syx` files only contain the voice data
+        let headerless = &bytes[HEADER_LEN..bytes.len() - 2];
+        assert_eq!(Dx7Dump::parse(headerless), Dx7Dump::parse(&bytes));
+    }
+
+    #[test]
+    fn rejects_invalid_dumps() {
+        let bytes = bank_dump();
+
+        let mut bad_checksum = bytes.clone();
+        bad_checksum[HEADER_LEN] ^= 1;
+        assert_eq!(Dx7Dump::parse(&bad_checksum), None);
+        assert_eq!(Dx7SysEx::from_buffer(&bad_checksum), None);
+
+        let mut bad_format = bytes.clone();
+        bad_format[3] = FORMAT_VOICE;
+        assert_eq!(Dx7Dump::parse(&bad_format), None);
+        bad_format[3] = 0x02;
+        assert_eq!(Dx7Dump::parse(&bad_format), None);
+
+        // Parameter changes use sub-status 1, the lower nibble is the channel
+        let mut parameter_change = bytes.clone();
+        parameter_change[2] = 0x10;
+        assert_eq!(Dx7SysEx::from_buffer(&parameter_change), None);
+        let mut other_channel = bytes.clone();
+        other_channel[2] = 0x05;
+        assert_eq!(Dx7SysEx::from_buffer(&other_channel), Some(Dx7SysEx::Bank));
+
+        let mut bad_byte_count = bytes.clone();
+        bad_byte_count[5] = 0x01;
+        assert_eq!(Dx7Dump::parse(&bad_byte_count), None);
+
+        assert_eq!(Dx7Dump::parse(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(Dx7Dump::parse(&[]), None);
+    }
+
+    #[test]
+    fn unpacks_packed_bit_fields() {
+        let mut data = init_voice();
+        // Operator 2 is the fifth operator in the dump
+        let operator = &mut data[4 * 17..5 * 17];
+        operator[11] = 2 | (3 << 2);
+        operator[12] = 5 | (10 << 3);
+        operator[13] = 1 | (6 << 2);
+        operator[15] = 1 | (17 << 1);
+        data[111] = (1 << 3) | 6;
+        data[116] = (5 << 4) | (4 << 1);
+
+        let voice = Dx7Voice::from_packed(&data);
+        let operator = &voice.operators[1];
+        assert_eq!(operator.left_curve, 2);
+        assert_eq!(operator.right_curve, 3);
+        assert_eq!(operator.rate_scaling, 5);
+        assert_eq!(operator.detune, 10);
+        assert_eq!(operator.amp_mod_sensitivity, 1);
+        assert_eq!(operator.velocity_sensitivity, 6);
+        assert!(operator.fixed);
+        assert_eq!(operator.coarse, 17);
+        assert_eq!(voice.feedback, 6);
+        assert!(!voice.lfo.key_sync);
+        assert_eq!(voice.lfo.wave, 4);
+        assert_eq!(voice.lfo.pitch_sensitivity, 5);
+
+        // The other operators are untouched
+        assert_eq!(
+            voice.operators[0],
+            Dx7Voice::from_packed(&init_voice()).operators[0]
+        );
+    }
+
+    #[test]
+    fn sysex_messages_only_carry_single_voices() {
+        let bytes = bank_dump();
+        let Some(Dx7Dump::Bank(voices)) = Dx7Dump::parse(&bytes) else {
+            panic!("The bank dump did not parse");
+        };
+        assert_eq!(Dx7SysEx::from_buffer(&bytes), Some(Dx7SysEx::Bank));
+
+        // A single voice dump stores the same voice unpacked
+        let packed = init_voice();
+        let mut data = [0; VOICE_LEN];
+        for op_idx in 0..NUM_OPERATORS {
+            let packed = &packed[op_idx * 17..];
+            let data = &mut data[op_idx * 21..];
+            data[..11].copy_from_slice(&packed[..11]);
+            data[11] = packed[11] & 0b11;
+            data[12] = packed[11] >> 2;
+            data[13] = packed[12] & 0b111;
+            data[14] = packed[13] & 0b11;
+            data[15] = packed[13] >> 2;
+            data[16] = packed[14];
+            data[17] = packed[15] & 1;
+            data[18] = packed[15] >> 1;
+            data[19] = packed[16];
+            data[20] = packed[12] >> 3;
+        }
+        data[126..134].copy_from_slice(&packed[102..110]);
+        data[134] = packed[110];
+        data[135] = packed[111] & 0b111;
+        data[136] = packed[111] >> 3;
+        data[137..141].copy_from_slice(&packed[112..116]);
+        data[141] = packed[116] & 1;
+        data[142] = (packed[116] >> 1) & 0b111;
+        data[143] = packed[116] >> 4;
+        data[144] = packed[117];
+        data[145..155].copy_from_slice(&packed[118..128]);
+
+        let mut bytes = vec![SYSEX_START, YAMAHA_ID, 0x00, FORMAT_VOICE, 0x01, 0x1b];
+        bytes.extend(data);
+        bytes.extend([compute_checksum(&data), SYSEX_END]);
+        assert_eq!(Dx7Dump::parse(&bytes), Some(Dx7Dump::Voice(voices[0])));
+        assert_eq!(
+            Dx7SysEx::from_buffer(&bytes),
+            Some(Dx7SysEx::Voice(voices[0]))
+        );
+    }
+}
@@ -1,16 +1,72 @@
-use crate::params::FmSynthParams;
-use nih_plug::{context::gui::GuiContext, params::smoothing::AtomicF32, util};
+use crate::{
+    dx7::{self, Dx7Dump, Dx7SysEx, Dx7Voice, BANK_SIZE},
+    params::FmSynthParams,
+};
+use nih_plug::{
+    context::gui::{GuiContext, ParamSetter},
+    params::smoothing::AtomicF32,
+    util,
+};
 use nih_plug_iced::*;
-use std::{sync::Arc, time::Duration};
+use std::{
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
 
 pub struct FmSynthEditorValues {
     pub peak_meter: AtomicF32,
+    /// The number of voices that are playing or releasing.
+    pub active_voices: AtomicUsize,
+    pub dx7_sysex: Mutex<Dx7SysExReceiver>,
+}
+
+/// Applies DX7 voices received over MIDI SysEx. Parameters can only be changed through a GUI
+/// context, and the plugin only gets one when the editor is opened. The context stays usable after
+/// the editor has been closed, so it's kept here. Voices received before the editor has been opened
+/// for the first time are ignored, and so are bulk dumps.
+#[derive(Default)]
+pub struct Dx7SysExReceiver {
+    context: Option<Arc<dyn GuiContext>>,
+    /// What happened to the last SysEx message, so the editor can show it.
+    status: Option<String>,
+}
+
+impl Dx7SysExReceiver {
+    /// Apply a voice received over MIDI if the editor has been opened before. This is called from
+    /// the plugin's task executor on the GUI thread.
+    pub fn receive(&mut self, message: Dx7SysEx, params: &FmSynthParams) {
+        self.status = Some(match (message, &self.context) {
+            (Dx7SysEx::Voice(voice), Some(context)) => {
+                voice.apply(&ParamSetter::new(context.as_ref()), params);
+                format!("Loaded '{}'", voice.name())
+            }
+            (Dx7SysEx::Voice(voice), None) => format!(
+                "Ignored '{}', voices received over MIDI are only applied after the editor has \
+                 been opened",
+                voice.name()
+            ),
+            (Dx7SysEx::Bank, _) => String::from(
+                "Ignored a bulk dump received over MIDI, load the bank from a .syx file instead",
+            ),
+        });
+    }
+
+    /// Keep the editor's GUI context so later voices can be applied.
+    fn connect(&mut self, context: Arc<dyn GuiContext>) {
+        self.context = Some(context);
+    }
 }
 
 impl Default for FmSynthEditorValues {
     fn default() -> Self {
         Self {
             peak_meter: AtomicF32::new(util::MINUS_INFINITY_DB),
+            active_voices: AtomicUsize::new(0),
+            dx7_sysex: Mutex::default(),
         }
     }
 }
@@ -18,6 +74,20 @@ impl Default for FmSynthEditorValues {
 #[derive(Default)]
 pub struct FmSynthEditorState {
     pub peak_meter: widgets::peak_meter::State,
+    pub dx7_path_input: text_input::State,
+    pub dx7_voice_number_input: text_input::State,
+    pub dx7_load_button: button::State,
+}
+
+#[derive(Debug, Clone)]
+pub enum Message {
+    /// Sent every frame so the results of DX7 SysEx messages received over MIDI can be shown.
+    Frame,
+    Dx7PathChanged(String),
+    Dx7VoiceNumberChanged(String),
+    /// Load the `.syx` file at the entered path. For banks, the voice with the entered number gets
+    /// applied.
+    LoadDx7File,
 }
 
 pub struct FmSynthEditor {
@@ -25,22 +95,35 @@ pub struct FmSynthEditor {
     values: Arc<FmSynthEditorValues>,
     state: FmSynthEditorState,
     context: Arc<dyn GuiContext>,
+
+    dx7_path: String,
+    /// The one based number of the voice to apply when a bank is loaded.
+    dx7_voice_number: String,
+    /// Either the name of the last applied DX7 voice, or the error from the last load attempt.
+    dx7_status: String,
 }
 
 impl IcedEditor for FmSynthEditor {
     type Executor = executor::Default;
-    type Message = ();
+    type Message = Message;
     type InitializationFlags = (Arc<FmSynthParams>, Arc<FmSynthEditorValues>);
 
     fn new(
         (params, values): Self::InitializationFlags,
         context: Arc<dyn GuiContext>,
     ) -> (Self, Command<Self::Message>) {
+        if let Ok(mut dx7_sysex) = values.dx7_sysex.lock() {
+            dx7_sysex.connect(context.clone());
+        }
+
         let editor = Self {
             context,
             params,
             values,
             state: FmSynthEditorState::default(),
+            dx7_path: String::new(),
+            dx7_voice_number: String::from("1"),
+            dx7_status: String::new(),
         };
 
         (editor, Command::none())
@@ -50,6 +133,15 @@ impl IcedEditor for FmSynthEditor {
         self.context.as_ref()
     }
 
+    fn subscription(
+        &self,
+        window_subs: &mut WindowSubs<Self::Message>,
+    ) -> Subscription<Self::Message> {
+        window_subs.on_frame = Some(Message::Frame);
+
+        Subscription::none()
+    }
+
     fn view(&mut self) -> nih_plug_iced::Element<'_, Self::Message> {
         Column::new()
             .align_items(Alignment::Center)
@@ -85,14 +177,68 @@ impl IcedEditor for FmSynthEditor {
                 )
                 .hold_time(Duration::from_millis(600)),
             )
+            .push(Text::new(format!(
+                "Voices: {}",
+                self.values.active_voices.load(Ordering::Relaxed)
+            )))
+            .push(Space::with_height(10.into()))
+            .push(
+                Row::new()
+                    .spacing(5)
+                    .push(
+                        TextInput::new(
+                            &mut self.state.dx7_path_input,
+                            "Path to a DX7 .syx file",
+                            &self.dx7_path,
+                            Message::Dx7PathChanged,
+                        )
+                        .padding(5)
+                        .width(Length::Fill),
+                    )
+                    .push(
+                        TextInput::new(
+                            &mut self.state.dx7_voice_number_input,
+                            "Voice",
+                            &self.dx7_voice_number,
+                            Message::Dx7VoiceNumberChanged,
+                        )
+                        .padding(5)
+                        .width(Length::Units(50)),
+                    )
+                    .push(
+                        Button::new(&mut self.state.dx7_load_button, Text::new("Load"))
+                            .on_press(Message::LoadDx7File),
+                    ),
+            )
+            .push(Text::new(&self.dx7_status).size(16))
             .into()
     }
 
     fn update(
         &mut self,
-        window: &mut nih_plug_iced::WindowQueue,
+        _window: &mut nih_plug_iced::WindowQueue,
         message: Self::Message,
     ) -> Command<Self::Message> {
+        match message {
+            Message::Frame => {
+                let sysex_status = self
+                    .values
+                    .dx7_sysex
+                    .lock()
+                    .ok()
+                    .and_then(|mut dx7_sysex| dx7_sysex.status.take());
+                if let Some(status) = sysex_status {
+                    self.dx7_status = status;
+                }
+            }
+            Message::Dx7PathChanged(path) => self.dx7_path = path,
+            Message::Dx7VoiceNumberChanged(voice_number) => self.dx7_voice_number = voice_number,
+            Message::LoadDx7File => match dx7::load_file(Path::new(&self.dx7_path)) {
+                Ok(dx7_dump) => self.apply_dx7_dump(&dx7_dump),
+                Err(err) => self.dx7_status = format!("Could not load '{}': {err}", self.dx7_path),
+            },
+        }
+
         Command::none()
     }
 
@@ -105,3 +251,28 @@ impl IcedEditor for FmSynthEditor {
         }
     }
 }
+
+impl FmSynthEditor {
+    /// Apply a DX7 voice to the parameters. For banks this uses the entered voice number.
+    fn apply_dx7_dump(&mut self, dx7_dump: &Dx7Dump) {
+        let voice = match dx7_dump {
+            Dx7Dump::Voice(voice) => voice,
+            Dx7Dump::Bank(voices) => match self.dx7_voice_number.trim().parse::<usize>() {
+                Ok(voice_number @ 1..=BANK_SIZE) => &voices[voice_number - 1],
+                _ => {
+                    self.dx7_status = format!("The voice number must be between 1 and {BANK_SIZE}");
+                    return;
+                }
+            },
+        };
+
+        self.apply_dx7_voice(voice);
+    }
+
+    fn apply_dx7_voice(&mut self, voice: &Dx7Voice) {
+        let setter = ParamSetter::new(self.context.as_ref());
+        voice.apply(&setter, &self.params);
+
+        self.dx7_status = format!("Loaded '{}'", voice.name());
+    }
+}
@@ -1,113 +1,1174 @@
 use nih_plug::prelude::*;
-use std::fmt::Debug;
+use std::{fmt::Debug, marker::PhantomData};
 
-#[derive(Debug, Clone, Copy)]
+/// How strongly the exponential curve bends. Higher values make the curve steeper at the start of
+/// the stage and flatter at the end.
+const EXPONENTIAL_CURVATURE: f32 = 5.0;
+
+/// Which kind of envelope generator to use.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EnvelopeMode {
+    /// An attack, hold, decay, sustain and release envelope that starts and ends at zero.
+    #[name = "AHDSR"]
+    Ahdsr,
+    /// A four rate and four level envelope like the DX7's. This starts at and returns to the
+    /// fourth level, and every level can be higher or lower than the previous one.
+    #[name = "Rate/Level"]
+    RateLevel,
+}
+
+/// With a velocity tracking amount of 100%, the softest notes make the envelope's times this many
+/// times longer, and the hardest notes make them this many times shorter.
+const VELOCITY_TRACKING_RANGE: f32 = 4.0;
+/// Releases that end at or below this level, which is -120 dB, finish as soon as the envelope drops
+/// below it. This guarantees that the envelope finishes, no matter how flat the curve's tail is.
+/// Envelopes at or below this level are considered silent.
+const RELEASE_SILENCE_THRESHOLD: f32 = 1e-6;
+/// When the sustain level changes while a note is sustaining, the envelope moves to the new level
+/// at a rate that would take this long to cross the full range.
+const SUSTAIN_SMOOTHING_MS: f32 = 20.0;
+/// Key tracking doesn't change the envelope's times for this note.
+const KEY_TRACKING_CENTER_NOTE: f32 = 60.0;
+
+/// What happens to a voice's envelopes when a new note reuses the voice.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RetriggerMode {
+    /// Every note starts a new voice with envelopes starting from the beginning.
+    #[name = "Reset"]
+    Reset,
+    /// Playing a note that's still sounding reuses its voice, and the envelopes restart their
+    /// attack from their current level.
+    #[name = "Analog"]
+    Analog,
+    /// Playing a note while another note on the same channel is held moves that voice to the new
+    /// note, and its envelopes keep going.
+    #[name = "Legato"]
+    Legato,
+}
+
+/// Whether and how an envelope repeats a range of its stages.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LoopMode {
+    #[name = "Off"]
+    Off,
+    /// The stages repeat while the note is held, and the envelope releases when it ends.
+    #[name = "Sustain Loop"]
+    Sustain,
+    /// The stages keep repeating after the note ends. These envelopes never release, so they
+    /// don't keep voices alive after their note has ended. Voices whose carriers use this are
+    /// faded out when their note ends.
+    #[name = "Free Loop"]
+    Free,
+}
+
+/// The shape of an envelope stage as it moves from its start value to its target.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum EnvelopeCurve {
+    #[name = "Linear"]
+    Linear,
+    /// Moves quickly at first and then slows down towards the target, like an analog envelope.
+    #[name = "Exponential"]
+    Exponential,
+    /// Raises the stage's progress to the segment's power. Powers below 1 move quickly at first,
+    /// powers above 1 move slowly at first.
+    #[name = "Power"]
+    Power,
+}
+
+/// The duration and shape of a single envelope stage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Segment {
+    pub duration_ms: f32,
+    pub curve: EnvelopeCurve,
+    /// The exponent used by [`EnvelopeCurve::Power`]. Ignored for the other curves.
+    pub power: f32,
+}
+
+/// The settings for an envelope. These are copied into the envelope with
+/// [`Envelope::set_settings()`] before the note starts.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnvelopeSettings {
+    pub mode: EnvelopeMode,
+    pub attack: Segment,
+    pub hold_ms: f32,
+    pub decay: Segment,
+    /// The sustain level, in `[0, 1]`.
+    pub sustain: f32,
+    pub release: Segment,
+    /// Only used in [`EnvelopeMode::RateLevel`]. The segments' curves are still taken from the
+    /// attack, decay and release segments.
+    pub rate_level: RateLevelSettings,
+    pub loop_mode: LoopMode,
+    /// The index of the first looping stage. Stages 0 to 2 are the attack, hold and decay stages,
+    /// or the first three rate/level stages.
+    pub loop_start: usize,
+    /// The index of the last looping stage. This is never smaller than `loop_start`.
+    pub loop_end: usize,
+    /// If set, the looping stages are stretched so one pass through them takes this long.
+    pub loop_duration_ms: Option<f32>,
+}
+
+/// The settings for [`EnvelopeMode::RateLevel`]. Stage 1 is the attack, stages 2 and 3 are the
+/// decay, and stage 4 is the release. The envelope sustains at level 3.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateLevelSettings {
+    /// The time in milliseconds each stage takes to move across the full `[0, 1]` range. Moving
+    /// between levels that are closer together takes proportionally less time.
+    pub rates_ms: [f32; 4],
+    /// The levels at the end of every stage, in `[0, 1]`.
+    pub levels: [f32; 4],
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 enum Phase {
     Attack,
-    Hold { remaining_ms: f32 },
+    Hold,
     Decay,
+    /// The second decay stage. Only used in [`EnvelopeMode::RateLevel`].
+    Decay2,
+    Sustain,
     Release,
+    /// The release stage has finished and the envelope outputs silence.
+    Finished,
 }
 
-#[derive(Debug, Clone, Default)]
+/// An AHDSR or rate/level envelope generator where every stage lasts exactly its set duration.
+/// Values lie in `[0, 1]`.
+#[derive(Debug, Clone)]
 pub struct Envelope<T>
 where
     T: Smoothable + Debug + Clone,
-    T::Atomic: Default + Debug,
 {
-    gain: Smoother<T>,
+    settings: EnvelopeSettings,
+    sample_rate: f32,
+    /// `None` until the first note on.
     phase: Option<Phase>,
+    /// Whether the note has ended. Free looping envelopes keep looping after this.
+    note_off: bool,
+    /// The envelope's current value.
+    value: f32,
+    /// The value at the start of the current stage, or at the point it was last replanned.
+    start_value: f32,
+    /// The value at the start of the current stage. Unlike `start_value`, replanning the stage does
+    /// not change this.
+    stage_start_value: f32,
+    /// The fraction of the current stage's duration that was left when it was last replanned. This
+    /// is 1 until the stage gets replanned.
+    stage_remaining: f32,
+    /// The value at the end of the current stage.
+    target_value: f32,
+    /// The number of samples that have been rendered in the current stage.
+    position: u32,
+    /// The length of the current stage in samples.
+    length: u32,
+    /// The difference in samples between the exact and the rounded lengths of the stages so far.
+    /// This prevents rounding errors from accumulating over multiple stages.
+    rounding_error: f32,
+    curve: EnvelopeCurve,
+    power: f32,
+
+    _value_type: PhantomData<T>,
 }
 
-impl<T> Envelope<T>
+impl<T> Default for Envelope<T>
 where
-    T: Smoothable + Debug + PartialEq,
-    T::Atomic: Default + Debug,
+    T: Smoothable + Debug + Clone,
 {
-    pub fn note_on(&mut self, sample_rate: f32, attack: f32, hold: f32, decay: f32, sustain: T) {
-        if attack > 0.0 {
-            self.gain.style = SmoothingStyle::Exponential(attack);
+    fn default() -> Self {
+        Self {
+            settings: EnvelopeSettings::default(),
+            sample_rate: 44_100.0,
+            phase: None,
+            note_off: false,
+            value: 0.0,
+            start_value: 0.0,
+            stage_start_value: 0.0,
+            stage_remaining: 1.0,
+            target_value: 0.0,
+            position: 0,
+            length: 0,
+            rounding_error: 0.0,
+            curve: EnvelopeCurve::Linear,
+            power: 1.0,
+            _value_type: PhantomData,
+        }
+    }
+}
 
-            self.gain.reset(T::from_f32(0.0));
-            self.gain.set_target(sample_rate, T::from_f32(1.0));
+impl Default for EnvelopeSettings {
+    fn default() -> Self {
+        let segment = Segment {
+            duration_ms: 0.0,
+            curve: EnvelopeCurve::Linear,
+            power: 1.0,
+        };
 
-            self.phase = Phase::Attack.into();
-        } else if hold > 0.0 {
-            self.gain.style = SmoothingStyle::Linear(1.0);
+        Self {
+            mode: EnvelopeMode::Ahdsr,
+            attack: segment,
+            hold_ms: 0.0,
+            decay: segment,
+            sustain: 1.0,
+            release: segment,
+            rate_level: RateLevelSettings {
+                rates_ms: [0.0; 4],
+                levels: [1.0, 1.0, 1.0, 0.0],
+            },
+            loop_mode: LoopMode::Off,
+            loop_start: 0,
+            loop_end: 2,
+            loop_duration_ms: None,
+        }
+    }
+}
 
-            self.gain.reset(T::from_f32(1.0));
+/// The factor an envelope time is multiplied by for a note's velocity in `[0, 1]`. A positive
+/// `amount` makes harder hits faster, and a negative amount makes them slower.
+pub fn velocity_time_scale(velocity: f32, amount: f32) -> f32 {
+    VELOCITY_TRACKING_RANGE.powf(-amount * (velocity * 2.0 - 1.0))
+}
 
-            self.phase = Phase::Hold { remaining_ms: hold }.into();
-        } else if decay > 0.0 {
-            self.gain.style = SmoothingStyle::Exponential(decay);
+/// The factor an envelope time is multiplied by for a note. With an `amount` of 1 the time halves
+/// every octave above middle C, and with an amount of -1 it doubles instead.
+pub fn key_time_scale(note: u8, amount: f32) -> f32 {
+    2.0f32.powf(-amount * (note as f32 - KEY_TRACKING_CENTER_NOTE) / 12.0)
+}
 
-            self.gain.reset(T::from_f32(1.0));
-            self.gain.set_target(sample_rate, sustain);
+impl EnvelopeCurve {
+    /// Map a stage's linear progress in `[0, 1]` to the curved progress in `[0, 1]`.
+    fn shape(self, progress: f32, power: f32) -> f32 {
+        match self {
+            EnvelopeCurve::Linear => progress,
+            EnvelopeCurve::Exponential => {
+                (1.0 - (-EXPONENTIAL_CURVATURE * progress).exp())
+                    / (1.0 - (-EXPONENTIAL_CURVATURE).exp())
+            }
+            EnvelopeCurve::Power => progress.powf(power),
+        }
+    }
+}
+
+impl Segment {
+    /// Multiply the segment's duration by `factor`.
+    pub fn scale_duration(self, factor: f32) -> Self {
+        Self {
+            duration_ms: self.duration_ms * factor,
+            ..self
+        }
+    }
+}
+
+impl EnvelopeSettings {
+    /// Multiply the duration of every stage by `factor`.
+    pub fn scale_durations(self, factor: f32) -> Self {
+        Self {
+            attack: self.attack.scale_duration(factor),
+            hold_ms: self.hold_ms * factor,
+            decay: self.decay.scale_duration(factor),
+            release: self.release.scale_duration(factor),
+            rate_level: RateLevelSettings {
+                rates_ms: self.rate_level.rates_ms.map(|rate_ms| rate_ms * factor),
+                ..self.rate_level
+            },
+            ..self
+        }
+    }
+}
+
+impl<T> Envelope<T>
+where
+    T: Smoothable + Debug + Clone,
+{
+    /// Replace all of the envelope's settings. This is used before a note starts to set the
+    /// mode, the curves, the loop and the rate/level envelope's stages. The times passed to
+    /// [`note_on()`][Self::note_on()] and [`note_off()`][Self::note_off()] take precedence over
+    /// the settings' AHDSR times. Use [`update()`][Self::update()] while the note is playing.
+    pub fn set_settings(&mut self, settings: &EnvelopeSettings) {
+        self.settings = *settings;
+    }
 
-            self.phase = Phase::Decay.into();
-        } else {
-            self.gain.style = SmoothingStyle::Exponential(decay);
+    /// Start the envelope at the attack stage. AHDSR envelopes start from zero and use the
+    /// `attack`, `hold` and `decay` times in milliseconds and the `sustain` level. Rate/level
+    /// envelopes start from their fourth level and take their stages from the settings instead.
+    /// Stages with a duration of zero are skipped.
+    pub fn note_on(&mut self, sample_rate: f32, attack: f32, hold: f32, decay: f32, sustain: T) {
+        self.value = match self.settings.mode {
+            EnvelopeMode::Ahdsr => 0.0,
+            EnvelopeMode::RateLevel => self.settings.rate_level.levels[3],
+        };
 
-            self.gain.reset(sustain);
+        self.start(sample_rate, attack, hold, decay, sustain);
+    }
 
-            self.phase = Phase::Decay.into();
+    /// Start the attack stage from the envelope's current value instead of resetting it like
+    /// [`note_on()`][Self::note_on()] does. This avoids clicks when a sounding voice gets
+    /// retriggered.
+    pub fn retrigger(&mut self, sample_rate: f32, attack: f32, hold: f32, decay: f32, sustain: T) {
+        if self.phase.is_none() {
+            self.note_on(sample_rate, attack, hold, decay, sustain);
+            return;
         }
+
+        self.start(sample_rate, attack, hold, decay, sustain);
     }
 
+    /// Start the release stage from the envelope's current value. AHDSR envelopes use the
+    /// `release` time in milliseconds, rate/level envelopes use their fourth rate. Free looping
+    /// envelopes ignore this and keep looping.
     pub fn note_off(&mut self, sample_rate: f32, release: f32) {
-        self.phase = Some(Phase::Release);
-        self.gain.style = SmoothingStyle::Exponential(release);
-        self.gain.set_target(sample_rate, T::from_f32(0.0));
+        self.settings.release.duration_ms = release;
+        self.sample_rate = sample_rate;
+        self.note_off = true;
+        self.rounding_error = 0.0;
+
+        if self.settings.loop_mode != LoopMode::Free || self.loop_length() == 0 {
+            self.enter(Phase::Release);
+        }
     }
 
+    /// Enter the attack stage with the note's AHDSR times. The envelope's value has already been
+    /// set.
+    fn start(&mut self, sample_rate: f32, attack: f32, hold: f32, decay: f32, sustain: T) {
+        self.settings.attack.duration_ms = attack;
+        self.settings.hold_ms = hold;
+        self.settings.decay.duration_ms = decay;
+        self.settings.sustain = sustain.to_f32();
+        self.sample_rate = sample_rate;
+        self.note_off = false;
+        self.rounding_error = 0.0;
+
+        self.enter(Phase::Attack);
+    }
+
+    /// Pick up changes to the decay, sustain and release settings while the note is playing. The
+    /// attack and hold settings are only used when the note starts. Stages that are in progress
+    /// continue from their current value, and changes to the sustain level are smoothed. The
+    /// current stage is only replanned when its own target or duration changes.
+    pub fn update(&mut self, settings: &EnvelopeSettings) {
+        let previous_settings = self.settings;
+        self.settings.decay = settings.decay;
+        self.settings.sustain = settings.sustain;
+        self.settings.release = settings.release;
+        self.settings.rate_level.rates_ms[1..].copy_from_slice(&settings.rate_level.rates_ms[1..]);
+        self.settings.rate_level.levels[2] = settings.rate_level.levels[2];
+        if self.settings == previous_settings {
+            return;
+        }
+
+        if let Some(phase @ (Phase::Decay | Phase::Decay2 | Phase::Release)) = self.phase {
+            let new_settings = self.settings;
+            self.settings = previous_settings;
+            let previous_plan = self.stage_plan(phase);
+            self.settings = new_settings;
+            if self.stage_plan(phase) != previous_plan {
+                self.replan(phase);
+            }
+        }
+    }
+
+    /// Whether the release stage has finished, or whether the note has ended for free looping
+    /// envelopes. Rate/level envelopes finish at their fourth level, so they're not necessarily
+    /// silent. See [`is_silent()`][Self::is_silent()].
     pub fn is_released(&self) -> bool {
-        matches!(self.phase, Some(Phase::Release)) && self.gain.previous_value() == T::from_f32(0.0)
+        match self.phase {
+            Some(Phase::Finished) => true,
+            Some(Phase::Release) => false,
+            _ => self.note_off,
+        }
+    }
+
+    /// Whether the envelope's current value is low enough to be inaudible. Envelopes that have
+    /// released but are not silent, like free looping envelopes and rate/level envelopes with a
+    /// non-zero fourth level, need to be faded out.
+    pub fn is_silent(&self) -> bool {
+        self.value <= RELEASE_SILENCE_THRESHOLD
     }
 
-    pub fn next_phase(&mut self, sample_rate: f32, hold: f32, decay: f32, sustain: T) {
-        let is_max = self.gain.previous_value() == T::from_f32(1.0);
+    /// Produce values for an entire block of audio. This is useful when iterating the same block of
+    /// audio multiple times. For instance when summing voices for a synthesizer.
+    /// `block_values[..block_len]` will be filled with the envelope's values. Stage transitions
+    /// happen at the exact sample they're due.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_len > block_values.len()`.
+    pub fn next_block(&mut self, block_values: &mut [T], block_len: usize) {
+        for value in &mut block_values[..block_len] {
+            *value = T::from_f32(self.next());
+        }
+    }
+
+    /// Advance the envelope by one sample and return the new value.
+    fn next(&mut self) -> f32 {
+        match self.phase {
+            Some(Phase::Attack | Phase::Hold | Phase::Decay | Phase::Decay2 | Phase::Release) => {
+                self.position += 1;
+                let progress = self.position as f32 / self.length as f32;
+                self.value = self.start_value
+                    + (self.target_value - self.start_value)
+                        * self.curve.shape(progress, self.power);
+
+                if self.position >= self.length {
+                    self.value = self.target_value;
+                    self.enter_next();
+                } else if self.phase == Some(Phase::Release)
+                    && self.target_value <= RELEASE_SILENCE_THRESHOLD
+                    && self.value <= RELEASE_SILENCE_THRESHOLD
+                {
+                    self.value = 0.0;
+                    self.enter(Phase::Finished);
+                }
+            }
+            Some(Phase::Sustain) => {
+                let max_step = 1000.0 / (SUSTAIN_SMOOTHING_MS * self.sample_rate);
+                self.value += (self.sustain_level() - self.value).clamp(-max_step, max_step);
+            }
+            Some(Phase::Finished) | None => (),
+        }
+
+        self.value
+    }
 
-        if !is_max {
+    /// Move on to the stage after the current one, or back to the start of the loop.
+    fn enter_next(&mut self) {
+        let stage_idx = self.phase.and_then(|phase| self.stage_idx(phase));
+        if self.settings.loop_mode != LoopMode::Off
+            && stage_idx == Some(self.settings.loop_end)
+            && self.loop_length() > 0
+        {
+            self.enter(self.stage_phase(self.settings.loop_start));
             return;
         }
 
-        match &mut self.phase {
-            Some(Phase::Attack) => {
-                self.phase = if hold > 0.0 {
-                    Phase::Hold { remaining_ms: hold }
-                } else {
-                    self.gain.style = SmoothingStyle::Exponential(decay);
+        match self.phase {
+            Some(Phase::Attack) => self.enter(Phase::Hold),
+            Some(Phase::Hold) => self.enter(Phase::Decay),
+            Some(Phase::Decay) => match self.settings.mode {
+                EnvelopeMode::Ahdsr => self.enter(Phase::Sustain),
+                EnvelopeMode::RateLevel => self.enter(Phase::Decay2),
+            },
+            Some(Phase::Decay2) => self.enter(Phase::Sustain),
+            Some(Phase::Release) => self.enter(Phase::Finished),
+            Some(Phase::Sustain | Phase::Finished) | None => (),
+        }
+    }
+
+    /// Start `phase` from the envelope's current value. If the stage has a duration of zero, then
+    /// this immediately jumps to the stage's target and moves on to the next stage.
+    fn enter(&mut self, phase: Phase) {
+        self.phase = Some(phase);
+        match self.stage(phase, self.value) {
+            Some((target_value, segment)) => {
+                self.start_value = self.value;
+                self.stage_start_value = self.value;
+                self.stage_remaining = 1.0;
+                self.target_value = target_value;
+                self.position = 0;
+                self.length = self.stage_length(self.stage_duration_ms(phase, segment));
+                self.curve = segment.curve;
+                self.power = segment.power;
 
-                    self.gain.set_target(sample_rate, sustain);
-                    Phase::Decay
+                if self.length == 0 {
+                    self.value = target_value;
+                    self.enter_next();
                 }
-                .into();
             }
-            Some(Phase::Hold { remaining_ms }) => {
-                *remaining_ms -= 1000.0 / sample_rate;
-                // Keep holding unless ms below 0.
-                if remaining_ms > &mut 0.0 {
-                    return;
+            None if phase == Phase::Sustain => self.value = self.sustain_level(),
+            None => {
+                self.value = match self.settings.mode {
+                    EnvelopeMode::Ahdsr => 0.0,
+                    EnvelopeMode::RateLevel => self.settings.rate_level.levels[3],
                 }
+            }
+        }
+    }
+
+    /// Restart the current stage from the envelope's current value after its settings have
+    /// changed. The new stage only lasts for the part of the stage that was still remaining.
+    fn replan(&mut self, phase: Phase) {
+        if let Some((target_value, segment)) = self.stage(phase, self.stage_start_value) {
+            let remaining =
+                self.stage_remaining * (1.0 - self.position as f32 / self.length as f32);
 
-                self.phase = Some(Phase::Decay);
-                self.gain.style = SmoothingStyle::Exponential(decay);
-                self.gain.set_target(sample_rate, sustain);
+            self.start_value = self.value;
+            self.stage_remaining = remaining;
+            self.target_value = target_value;
+            self.position = 0;
+            self.length = self.stage_length(self.stage_duration_ms(phase, segment) * remaining);
+            self.curve = segment.curve;
+            self.power = segment.power;
+
+            if self.length == 0 {
+                self.value = target_value;
+                self.enter_next();
             }
-            _ => (),
         }
     }
 
-    /// Produce smoothed values for an entire block of audio. This is useful when iterating the same
-    /// block of audio multiple times. For instance when summing voices for a synthesizer.
-    /// `block_values[..block_len]` will be filled with the smoothed values. This is simply a
-    /// convenient function for [`next_block_exact()`][Self::next_block_exact()] when iterating over
-    /// variable length blocks with a known maximum size.
-    ///
-    /// # Panics
-    ///
-    /// Panics if `block_len > block_values.len()`.
-    pub fn next_block(&mut self, block_values: &mut [T], block_len: usize) {
-        self.gain.next_block(block_values, block_len)
+    /// The target value, the stretched duration and the segment for the stage the envelope is in.
+    /// [`update()`][Self::update()] only replans the stage when this changes.
+    fn stage_plan(&self, phase: Phase) -> Option<(f32, f32, Segment)> {
+        self.stage(phase, self.stage_start_value)
+            .map(|(target_value, segment)| {
+                (
+                    target_value,
+                    self.stage_duration_ms(phase, segment),
+                    segment,
+                )
+            })
+    }
+
+    /// The target value and segment for a stage starting at `from_value`. Returns `None` for the
+    /// sustain and finished stages, since they don't move towards a target.
+    fn stage(&self, phase: Phase, from_value: f32) -> Option<(f32, Segment)> {
+        // Rate/level envelopes don't have a hold stage
+        let hold = Segment {
+            duration_ms: match self.settings.mode {
+                EnvelopeMode::Ahdsr => self.settings.hold_ms,
+                EnvelopeMode::RateLevel => 0.0,
+            },
+            curve: EnvelopeCurve::Linear,
+            power: 1.0,
+        };
+
+        match (self.settings.mode, phase) {
+            (_, Phase::Sustain | Phase::Finished) => None,
+            (_, Phase::Hold) => Some((from_value, hold)),
+            (EnvelopeMode::Ahdsr, Phase::Attack) => Some((1.0, self.settings.attack)),
+            (EnvelopeMode::Ahdsr, Phase::Decay | Phase::Decay2) => {
+                Some((self.settings.sustain, self.settings.decay))
+            }
+            (EnvelopeMode::Ahdsr, Phase::Release) => Some((0.0, self.settings.release)),
+            (EnvelopeMode::RateLevel, Phase::Attack) => {
+                Some(self.rate_level_stage(0, from_value, self.settings.attack))
+            }
+            (EnvelopeMode::RateLevel, Phase::Decay) => {
+                Some(self.rate_level_stage(1, from_value, self.settings.decay))
+            }
+            (EnvelopeMode::RateLevel, Phase::Decay2) => {
+                Some(self.rate_level_stage(2, from_value, self.settings.decay))
+            }
+            (EnvelopeMode::RateLevel, Phase::Release) => {
+                Some(self.rate_level_stage(3, from_value, self.settings.release))
+            }
+        }
+    }
+
+    /// The duration of a stage's segment, stretched if the stage is part of a tempo synced loop.
+    fn stage_duration_ms(&self, phase: Phase, segment: Segment) -> f32 {
+        match self.stage_idx(phase) {
+            Some(stage_idx) if self.settings.loop_mode != LoopMode::Off => {
+                segment.duration_ms * self.loop_stretch(stage_idx)
+            }
+            _ => segment.duration_ms,
+        }
+    }
+
+    /// The target level and segment for a rate/level stage starting from `from_value`. The
+    /// stage's duration depends on how far it has to move.
+    fn rate_level_stage(
+        &self,
+        stage_idx: usize,
+        from_value: f32,
+        segment: Segment,
+    ) -> (f32, Segment) {
+        let RateLevelSettings { rates_ms, levels } = self.settings.rate_level;
+        let target_value = levels[stage_idx];
+
+        (
+            target_value,
+            Segment {
+                duration_ms: rates_ms[stage_idx] * (target_value - from_value).abs(),
+                ..segment
+            },
+        )
+    }
+
+    /// The level the envelope sustains at while the note is held.
+    fn sustain_level(&self) -> f32 {
+        match self.settings.mode {
+            EnvelopeMode::Ahdsr => self.settings.sustain,
+            EnvelopeMode::RateLevel => self.settings.rate_level.levels[2],
+        }
+    }
+
+    /// The phase for a stage that can be looped. See [`EnvelopeSettings::loop_start`].
+    fn stage_phase(&self, stage_idx: usize) -> Phase {
+        match (self.settings.mode, stage_idx) {
+            (_, 0) => Phase::Attack,
+            (EnvelopeMode::Ahdsr, 1) => Phase::Hold,
+            (EnvelopeMode::Ahdsr, _) => Phase::Decay,
+            (EnvelopeMode::RateLevel, 1) => Phase::Decay,
+            (EnvelopeMode::RateLevel, _) => Phase::Decay2,
+        }
+    }
+
+    /// The inverse of [`stage_phase()`][Self::stage_phase()]. Returns `None` for the stages that
+    /// cannot be looped.
+    fn stage_idx(&self, phase: Phase) -> Option<usize> {
+        match (self.settings.mode, phase) {
+            (_, Phase::Attack) => Some(0),
+            (EnvelopeMode::Ahdsr, Phase::Hold) => Some(1),
+            (EnvelopeMode::Ahdsr, Phase::Decay) => Some(2),
+            (EnvelopeMode::RateLevel, Phase::Decay) => Some(1),
+            (EnvelopeMode::RateLevel, Phase::Decay2) => Some(2),
+            _ => None,
+        }
+    }
+
+    /// The unstretched duration of a looping stage once the loop has started. Rate/level stages
+    /// at the start of the loop move from the level at the end of the loop.
+    fn loop_stage_ms(&self, stage_idx: usize) -> f32 {
+        match self.settings.mode {
+            EnvelopeMode::Ahdsr => match stage_idx {
+                0 => self.settings.attack.duration_ms,
+                1 => self.settings.hold_ms,
+                _ => self.settings.decay.duration_ms,
+            },
+            EnvelopeMode::RateLevel => {
+                let RateLevelSettings { rates_ms, levels } = self.settings.rate_level;
+                let from_idx = if stage_idx == self.settings.loop_start {
+                    self.settings.loop_end
+                } else {
+                    stage_idx - 1
+                };
+
+                rates_ms[stage_idx] * (levels[stage_idx] - levels[from_idx]).abs()
+            }
+        }
+    }
+
+    /// The factor the looping stages' durations are multiplied by to make one pass through the
+    /// loop last [`EnvelopeSettings::loop_duration_ms`].
+    fn loop_stretch(&self, stage_idx: usize) -> f32 {
+        let loop_range = self.settings.loop_start..=self.settings.loop_end;
+        if !loop_range.contains(&stage_idx) {
+            return 1.0;
+        }
+
+        let natural_ms: f32 = loop_range.map(|idx| self.loop_stage_ms(idx)).sum();
+        match self.settings.loop_duration_ms {
+            Some(loop_duration_ms) if natural_ms > 0.0 => loop_duration_ms / natural_ms,
+            _ => 1.0,
+        }
+    }
+
+    /// The length in samples of one pass through the looping stages. Looping is skipped if this
+    /// is zero since the envelope would never advance.
+    fn loop_length(&self) -> u32 {
+        (self.settings.loop_start..=self.settings.loop_end)
+            .map(|idx| self.samples(self.loop_stage_ms(idx) * self.loop_stretch(idx)))
+            .sum()
+    }
+
+    /// Convert a stage's duration in milliseconds to a whole number of samples. The rounding error
+    /// is carried over to the next stage, so every stage transition happens within half a sample
+    /// of its exact time since the note started or ended.
+    fn stage_length(&mut self, duration_ms: f32) -> u32 {
+        let exact_length = duration_ms / 1000.0 * self.sample_rate;
+        if exact_length <= 0.0 {
+            return 0;
+        }
+
+        let length = (exact_length + self.rounding_error).round().max(0.0);
+        self.rounding_error += exact_length - length;
+
+        length as u32
+    }
+
+    /// Convert a duration in milliseconds to a whole number of samples.
+    fn samples(&self, duration_ms: f32) -> u32 {
+        (duration_ms / 1000.0 * self.sample_rate).round() as u32
+    }
+}
+
+/// A linear segment with the given duration, for tests.
+#[cfg(test)]
+pub(crate) fn linear(duration_ms: f32) -> Segment {
+    Segment {
+        duration_ms,
+        curve: EnvelopeCurve::Linear,
+        power: 1.0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATES: [f32; 5] = [22_050.0, 44_100.0, 48_000.0, 96_000.0, 192_000.0];
+    /// The block size used by the plugin. Rendering in blocks checks that transitions happen
+    /// inside of blocks and not at their boundaries.
+    const BLOCK_SIZE: usize = 64;
+
+    /// Set all of the envelope's settings and start a note with them, like the plugin does.
+    fn note_on(envelope: &mut Envelope<f32>, sample_rate: f32, settings: &EnvelopeSettings) {
+        envelope.set_settings(settings);
+        envelope.note_on(
+            sample_rate,
+            settings.attack.duration_ms,
+            settings.hold_ms,
+            settings.decay.duration_ms,
+            settings.sustain,
+        );
+    }
+
+    fn ahdsr(attack_ms: f32, hold_ms: f32, decay_ms: f32, sustain: f32) -> EnvelopeSettings {
+        EnvelopeSettings {
+            attack: linear(attack_ms),
+            hold_ms,
+            decay: linear(decay_ms),
+            sustain,
+            ..EnvelopeSettings::default()
+        }
+    }
+
+    fn render(envelope: &mut Envelope<f32>, num_samples: usize) -> Vec<f32> {
+        let mut values = vec![0.0; num_samples];
+        for block in values.chunks_mut(BLOCK_SIZE) {
+            let block_len = block.len();
+            envelope.next_block(block, block_len);
+        }
+
+        values
+    }
+
+    /// The number of samples rendered up to and including the first value matching `predicate`,
+    /// starting at `from`. This is the sample a stage ends on.
+    fn samples_until(values: &[f32], from: usize, predicate: impl Fn(f32) -> bool) -> usize {
+        from + values[from..]
+            .iter()
+            .position(|value| predicate(*value))
+            .expect("The envelope never reached the expected value")
+            + 1
+    }
+
+    fn assert_within_one_sample(actual_samples: usize, expected_ms: f32, sample_rate: f32) {
+        let expected_samples = expected_ms / 1000.0 * sample_rate;
+        assert!(
+            (actual_samples as f32 - expected_samples).abs() <= 1.0,
+            "Expected a transition after {expected_samples} samples at {sample_rate} Hz, got \
+             {actual_samples}"
+        );
+    }
+
+    #[test]
+    fn stage_transitions_are_sample_accurate() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &ahdsr(10.3, 5.7, 20.9, 0.5));
+            let values = render(&mut envelope, sample_rate as usize);
+
+            let attack_end = samples_until(&values, 0, |value| value >= 1.0);
+            assert_within_one_sample(attack_end, 10.3, sample_rate);
+            // The hold stage ends on the last sample before the decay starts
+            let hold_end = samples_until(&values, attack_end, |value| value < 1.0) - 1;
+            assert_within_one_sample(hold_end, 10.3 + 5.7, sample_rate);
+            let decay_end = samples_until(&values, hold_end, |value| value <= 0.5);
+            assert_within_one_sample(decay_end, 10.3 + 5.7 + 20.9, sample_rate);
+            assert!(values[decay_end..].iter().all(|value| *value == 0.5));
+
+            envelope.note_off(sample_rate, 30.1);
+            let values = render(&mut envelope, sample_rate as usize);
+            let release_end = samples_until(&values, 0, |value| value <= 0.0);
+            assert_within_one_sample(release_end, 30.1, sample_rate);
+            assert!(envelope.is_released());
+        }
+    }
+
+    #[test]
+    fn looping_stages_dont_drift() {
+        const NUM_LOOPS: usize = 50;
+
+        for sample_rate in SAMPLE_RATES {
+            let settings = EnvelopeSettings {
+                loop_mode: LoopMode::Sustain,
+                ..ahdsr(1.31, 0.77, 2.93, 0.25)
+            };
+            let loop_ms = 1.31 + 0.77 + 2.93;
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+            let values = render(
+                &mut envelope,
+                ((NUM_LOOPS as f32 + 1.0) * loop_ms / 1000.0 * sample_rate) as usize,
+            );
+
+            // Every pass through the loop starts with an attack from the sustain level to 1
+            let mut loop_start = 0;
+            for loop_idx in 0..NUM_LOOPS {
+                let attack_end = samples_until(&values, loop_start, |value| value >= 1.0);
+                assert_within_one_sample(attack_end, 1.31 + loop_idx as f32 * loop_ms, sample_rate);
+
+                loop_start = samples_until(&values, attack_end, |value| value <= 0.25);
+            }
+        }
+    }
+
+    #[test]
+    fn free_loops_keep_looping_after_note_off() {
+        for sample_rate in SAMPLE_RATES {
+            let settings = EnvelopeSettings {
+                loop_mode: LoopMode::Free,
+                ..ahdsr(10.0, 0.0, 10.0, 0.2)
+            };
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+            render(&mut envelope, (0.005 * sample_rate) as usize);
+
+            // The envelope does not keep its voice alive, but the voice still needs to be faded
+            // out since the envelope is not silent
+            envelope.note_off(sample_rate, 10.0);
+            assert!(envelope.is_released());
+            assert!(!envelope.is_silent());
+            let values = render(&mut envelope, (0.1 * sample_rate) as usize);
+            assert!(values.iter().all(|value| *value >= 0.2));
+            assert!(values.iter().any(|value| *value >= 1.0));
+        }
+    }
+
+    #[test]
+    fn rate_level_stage_durations_depend_on_distance() {
+        for sample_rate in SAMPLE_RATES {
+            let settings = EnvelopeSettings {
+                mode: EnvelopeMode::RateLevel,
+                rate_level: RateLevelSettings {
+                    rates_ms: [100.0, 40.0, 200.0, 80.0],
+                    levels: [1.0, 0.5, 0.75, 0.0],
+                },
+                ..EnvelopeSettings::default()
+            };
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+            let values = render(&mut envelope, sample_rate as usize);
+
+            let stage_1_end = samples_until(&values, 0, |value| value >= 1.0);
+            assert_within_one_sample(stage_1_end, 100.0, sample_rate);
+            let stage_2_end = samples_until(&values, stage_1_end, |value| value <= 0.5);
+            assert_within_one_sample(stage_2_end, 100.0 + 40.0 * 0.5, sample_rate);
+            // The level rises again during the third stage
+            let stage_3_end = samples_until(&values, stage_2_end, |value| value >= 0.75);
+            assert_within_one_sample(stage_3_end, 100.0 + 40.0 * 0.5 + 200.0 * 0.25, sample_rate);
+        }
+    }
+
+    #[test]
+    fn rate_level_releases_end_at_level_4() {
+        for sample_rate in SAMPLE_RATES {
+            for level_4 in [0.0, 1e-7, 0.25] {
+                let settings = EnvelopeSettings {
+                    mode: EnvelopeMode::RateLevel,
+                    rate_level: RateLevelSettings {
+                        rates_ms: [10.0, 10.0, 10.0, 10.0],
+                        levels: [1.0, 0.5, 0.5, level_4],
+                    },
+                    ..EnvelopeSettings::default()
+                };
+                let mut envelope = Envelope::<f32>::default();
+                note_on(&mut envelope, sample_rate, &settings);
+                render(&mut envelope, (0.02 * sample_rate) as usize);
+
+                envelope.note_off(sample_rate, 0.0);
+                assert!(!envelope.is_released());
+                let values = render(&mut envelope, (0.01 * sample_rate) as usize);
+                assert!(envelope.is_released());
+                assert_eq!(*values.last().unwrap(), level_4);
+
+                // Releases that end above the silence threshold need to be faded out
+                assert_eq!(envelope.is_silent(), level_4 <= RELEASE_SILENCE_THRESHOLD);
+            }
+        }
+    }
+
+    /// Render `num_samples` samples one by one, together with the stage the envelope is in after
+    /// producing them. The last sample of a stage already has the next stage's starting value, so
+    /// it's tagged with the next stage.
+    fn render_stages(envelope: &mut Envelope<f32>, num_samples: usize) -> Vec<(Phase, f32)> {
+        (0..num_samples)
+            .map(|_| {
+                let value = envelope.next();
+                (
+                    envelope.phase.expect("The envelope was never started"),
+                    value,
+                )
+            })
+            .collect()
+    }
+
+    /// Check that the values are bounded and that every stage only moves in its own direction.
+    /// `settings` is used to describe the failing case.
+    fn assert_well_behaved(values: &[(Phase, f32)], settings: &EnvelopeSettings) {
+        for window in values.windows(2) {
+            let [(previous_phase, previous_value), (phase, value)] = [window[0], window[1]];
+            assert!(
+                (0.0..=1.0).contains(&value),
+                "{value} is out of bounds in {phase:?} for {settings:?}"
+            );
+            if phase != previous_phase {
+                continue;
+            }
+
+            let in_direction = match phase {
+                Phase::Attack => value >= previous_value,
+                Phase::Hold | Phase::Sustain | Phase::Finished => value == previous_value,
+                Phase::Decay | Phase::Decay2 | Phase::Release => value <= previous_value,
+            };
+            assert!(
+                in_direction,
+                "{previous_value} to {value} is not monotonic in {phase:?} for {settings:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn envelope_is_bounded_monotonic_and_releases() {
+        let curves = [
+            (EnvelopeCurve::Linear, 1.0),
+            (EnvelopeCurve::Exponential, 1.0),
+            (EnvelopeCurve::Power, 0.5),
+            (EnvelopeCurve::Power, 3.0),
+        ];
+
+        for sample_rate in SAMPLE_RATES {
+            for attack_ms in [0.0, 0.01, 5.0, 50.0] {
+                for hold_ms in [0.0, 10.0] {
+                    for decay_ms in [0.0, 30.0] {
+                        for sustain in [0.0, 0.6, 1.0] {
+                            for (curve, power) in curves {
+                                for release_ms in [0.0, 20.0] {
+                                    let segment = |duration_ms| Segment {
+                                        duration_ms,
+                                        curve,
+                                        power,
+                                    };
+                                    let settings = EnvelopeSettings {
+                                        attack: segment(attack_ms),
+                                        decay: segment(decay_ms),
+                                        release: segment(release_ms),
+                                        ..ahdsr(attack_ms, hold_ms, decay_ms, sustain)
+                                    };
+
+                                    let held_samples = ((attack_ms + hold_ms + decay_ms) / 1000.0
+                                        * sample_rate)
+                                        as usize
+                                        + 2;
+                                    let mut envelope = Envelope::<f32>::default();
+                                    note_on(&mut envelope, sample_rate, &settings);
+                                    let mut values = render_stages(&mut envelope, held_samples);
+                                    assert_eq!(envelope.phase, Some(Phase::Sustain));
+                                    assert_eq!(envelope.value, sustain);
+                                    assert!(!envelope.is_released());
+
+                                    let released_samples =
+                                        (release_ms / 1000.0 * sample_rate) as usize + 2;
+                                    envelope.note_off(sample_rate, release_ms);
+                                    values.extend(render_stages(&mut envelope, released_samples));
+                                    assert!(envelope.is_released(), "{settings:?} never released");
+                                    assert_eq!(envelope.value, 0.0);
+
+                                    assert_well_behaved(&values, &settings);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn zero_attack_starts_at_full_level() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &ahdsr(0.0, 10.0, 10.0, 0.5));
+            assert_eq!(envelope.phase, Some(Phase::Hold));
+
+            let values = render(&mut envelope, 1);
+            assert_eq!(values[0], 1.0);
+        }
+    }
+
+    #[test]
+    fn zero_hold_decays_right_after_attack() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &ahdsr(10.0, 0.0, 10.0, 0.5));
+            let values = render(&mut envelope, sample_rate as usize);
+
+            let attack_end = samples_until(&values, 0, |value| value >= 1.0);
+            assert!(values[attack_end] < 1.0);
+        }
+    }
+
+    #[test]
+    fn zero_decay_jumps_to_sustain() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &ahdsr(10.0, 5.0, 0.0, 0.5));
+            let values = render(&mut envelope, sample_rate as usize);
+
+            // The hold stage's last sample already jumps to the sustain level
+            let attack_end = samples_until(&values, 0, |value| value >= 1.0);
+            let hold_end = samples_until(&values, attack_end, |value| value < 1.0);
+            assert_within_one_sample(hold_end, 15.0, sample_rate);
+            assert_eq!(values[hold_end - 1], 0.5);
+            assert_eq!(envelope.phase, Some(Phase::Sustain));
+        }
+    }
+
+    #[test]
+    fn zero_stages_sustain_immediately() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &ahdsr(0.0, 0.0, 0.0, 0.3));
+            assert_eq!(envelope.phase, Some(Phase::Sustain));
+            assert_eq!(render(&mut envelope, 1)[0], 0.3);
+
+            envelope.note_off(sample_rate, 0.0);
+            assert!(envelope.is_released());
+            assert_eq!(render(&mut envelope, 1)[0], 0.0);
+        }
+    }
+
+    #[test]
+    fn release_starts_from_current_value() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &ahdsr(100.0, 0.0, 0.0, 1.0));
+            // Release halfway through the attack
+            let values = render(&mut envelope, (0.05 * sample_rate) as usize);
+            let released_value = *values.last().unwrap();
+            assert!(released_value > 0.4 && released_value < 0.6);
+
+            envelope.note_off(sample_rate, 10.0);
+            assert!(!envelope.is_released());
+            let values = render(&mut envelope, sample_rate as usize);
+            assert!(values[0] < released_value);
+            let release_end = samples_until(&values, 0, |value| value <= 0.0);
+            assert_within_one_sample(release_end, 10.0, sample_rate);
+            assert!(envelope.is_released());
+        }
+    }
+
+    #[test]
+    fn sustain_changes_are_smoothed() {
+        for sample_rate in SAMPLE_RATES {
+            let settings = ahdsr(1.0, 0.0, 1.0, 0.8);
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+            render(&mut envelope, (0.01 * sample_rate) as usize);
+            assert_eq!(envelope.value, 0.8);
+
+            envelope.update(&EnvelopeSettings {
+                sustain: 0.2,
+                ..settings
+            });
+            let values = render(&mut envelope, (0.1 * sample_rate) as usize);
+            let max_step = 1000.0 / (SUSTAIN_SMOOTHING_MS * sample_rate) + f32::EPSILON;
+            assert!((0.8 - values[0]) <= max_step);
+            assert!(values
+                .windows(2)
+                .all(|window| (window[0] - window[1]).abs() <= max_step));
+            assert_eq!(*values.last().unwrap(), 0.2);
+        }
+    }
+
+    #[test]
+    fn releases_finish_at_the_silence_threshold() {
+        for sample_rate in SAMPLE_RATES {
+            let mut envelope = Envelope::<f32>::default();
+            // This curve has a long and very flat tail, so it drops below the threshold before the
+            // stage has finished
+            let settings = EnvelopeSettings {
+                release: Segment {
+                    duration_ms: 10_000.0,
+                    curve: EnvelopeCurve::Power,
+                    power: 0.1,
+                },
+                ..ahdsr(0.0, 0.0, 0.0, 1.0)
+            };
+            note_on(&mut envelope, sample_rate, &settings);
+            envelope.note_off(sample_rate, 10_000.0);
+            let release_samples = (10.0 * sample_rate) as usize;
+            let values = render(&mut envelope, release_samples);
+
+            let release_end = samples_until(&values, 0, |value| value <= RELEASE_SILENCE_THRESHOLD);
+            assert!(release_end < release_samples - 1);
+            assert!(values[release_end - 2] > RELEASE_SILENCE_THRESHOLD);
+            assert!(values[release_end - 1..].iter().all(|value| *value == 0.0));
+            assert!(envelope.is_released());
+            assert!(envelope.is_silent());
+        }
+    }
+
+    #[test]
+    fn unrelated_changes_dont_replan_the_current_stage() {
+        for sample_rate in SAMPLE_RATES {
+            let settings = EnvelopeSettings {
+                decay: Segment {
+                    duration_ms: 100.0,
+                    curve: EnvelopeCurve::Exponential,
+                    power: 1.0,
+                },
+                ..ahdsr(0.0, 0.0, 100.0, 0.2)
+            };
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+            let mut reference = envelope.clone();
+
+            // Changing the release time while decaying should not restart the decay's curve
+            let mut values = Vec::new();
+            for block_idx in 0..(0.1 * sample_rate) as usize / BLOCK_SIZE {
+                envelope.update(&EnvelopeSettings {
+                    release: linear(block_idx as f32),
+                    ..settings
+                });
+                values.extend(render(&mut envelope, BLOCK_SIZE));
+            }
+            assert_eq!(values, render(&mut reference, values.len()));
+        }
+    }
+
+    #[test]
+    fn repeated_changes_keep_the_remaining_time() {
+        for sample_rate in SAMPLE_RATES {
+            let settings = ahdsr(0.0, 0.0, 100.0, 0.2);
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+
+            // Automating the decay replans it for every block, which should not stretch the stage
+            let mut values = Vec::new();
+            for block_idx in 0..(0.2 * sample_rate) as usize / BLOCK_SIZE {
+                envelope.update(&EnvelopeSettings {
+                    decay: linear(if block_idx % 2 == 0 { 100.0 } else { 100.001 }),
+                    ..settings
+                });
+                values.extend(render(&mut envelope, BLOCK_SIZE));
+            }
+            let decay_end = samples_until(&values, 0, |value| value <= 0.2);
+            assert_within_one_sample(decay_end, 100.0, sample_rate);
+        }
+    }
+
+    #[test]
+    fn release_changes_affect_released_notes() {
+        for sample_rate in SAMPLE_RATES {
+            let settings = ahdsr(0.0, 0.0, 0.0, 1.0);
+            let mut envelope = Envelope::<f32>::default();
+            note_on(&mut envelope, sample_rate, &settings);
+            envelope.note_off(sample_rate, 100.0);
+            let values = render(&mut envelope, (0.025 * sample_rate) as usize);
+            let released_value = *values.last().unwrap();
+
+            // A quarter of the release has passed, so the rest of it now takes 15 ms
+            envelope.update(&EnvelopeSettings {
+                release: linear(20.0),
+                ..settings
+            });
+            let values = render(&mut envelope, sample_rate as usize);
+            assert!(values[0] < released_value && released_value - values[0] < 0.01);
+            let release_end = samples_until(&values, 0, |value| value <= 0.0);
+            assert_within_one_sample(release_end, 15.0, sample_rate);
+            assert!(envelope.is_released());
+        }
     }
 }
@@ -0,0 +1,212 @@
+use nih_plug::prelude::*;
+use rand::Rng;
+use rand_pcg::Pcg32;
+use std::f32::consts::TAU;
+
+/// The number of LFOs.
+pub const NUM_LFOS: usize = 2;
+
+/// The shape of an LFO's waveform. All shapes produce values in `[-1, 1]`.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoShape {
+    #[name = "Sine"]
+    Sine,
+    #[name = "Triangle"]
+    Triangle,
+    /// Rises from -1 to 1 and then jumps back down.
+    #[name = "Saw"]
+    Saw,
+    #[name = "Square"]
+    Square,
+    /// Holds a new random value for every cycle.
+    #[name = "Sample & Hold"]
+    SampleAndHold,
+}
+
+/// Whether an LFO is shared by all voices or whether every voice gets its own.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum LfoMode {
+    /// One LFO is shared by all voices. Its delay and fade in restart when a note starts while no
+    /// other notes are held.
+    #[name = "Global"]
+    Global,
+    /// Every voice has its own LFO which starts with the voice.
+    #[name = "Per Voice"]
+    PerVoice,
+}
+
+/// The settings for an LFO. These are read every block.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LfoSettings {
+    pub shape: LfoShape,
+    pub rate_hz: f32,
+    /// How long the LFO stays silent after a note starts.
+    pub delay_ms: f32,
+    /// How long the LFO takes to fade in after the delay.
+    pub fade_ms: f32,
+}
+
+/// A low frequency oscillator with a delay and a fade in.
+#[derive(Debug, Clone, Default)]
+pub struct Lfo {
+    /// The phase in `[0, 1)`.
+    phase: f32,
+    /// The current value for the sample and hold shape.
+    held_value: f32,
+    /// The number of samples since the last note on, used for the delay and fade in.
+    age: u32,
+}
+
+impl LfoShape {
+    /// Sample the shape at `phase` in `[0, 1)`. `held_value` is returned for sample and hold.
+    fn sample(self, phase: f32, held_value: f32) -> f32 {
+        match self {
+            LfoShape::Sine => (phase * TAU).sin(),
+            LfoShape::Triangle => 1.0 - 4.0 * (phase - 0.5).abs(),
+            LfoShape::Saw => phase * 2.0 - 1.0,
+            LfoShape::Square => {
+                if phase < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+            LfoShape::SampleAndHold => held_value,
+        }
+    }
+}
+
+impl Lfo {
+    /// Restart the LFO's delay and fade in for a new note. If `key_sync` is enabled the phase
+    /// restarts at zero, and otherwise the LFO keeps running from where it was.
+    pub fn note_on(&mut self, key_sync: bool, prng: &mut Pcg32) {
+        self.age = 0;
+        if key_sync {
+            self.phase = 0.0;
+            self.held_value = prng.gen_range(-1.0..=1.0);
+        }
+    }
+
+    /// Start a per-voice LFO that is not key synced at a random phase, so the voices' LFOs don't
+    /// all move in lockstep.
+    pub fn randomize_phase(&mut self, prng: &mut Pcg32) {
+        self.phase = prng.gen();
+        self.held_value = prng.gen_range(-1.0..=1.0);
+    }
+
+    /// Fill `block_values[..block_len]` with the LFO's values in `[-1, 1]`, including the delay
+    /// and fade in.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `block_len > block_values.len()`.
+    pub fn next_block(
+        &mut self,
+        block_values: &mut [f32],
+        block_len: usize,
+        settings: &LfoSettings,
+        sample_rate: f32,
+        prng: &mut Pcg32,
+    ) {
+        let phase_delta = settings.rate_hz / sample_rate;
+        let delay_samples = settings.delay_ms / 1000.0 * sample_rate;
+        let fade_samples = settings.fade_ms / 1000.0 * sample_rate;
+
+        for value in &mut block_values[..block_len] {
+            let fade = if fade_samples > 0.0 {
+                ((self.age as f32 - delay_samples) / fade_samples).clamp(0.0, 1.0)
+            } else if self.age as f32 >= delay_samples {
+                1.0
+            } else {
+                0.0
+            };
+            *value = settings.shape.sample(self.phase, self.held_value) * fade;
+
+            self.age = self.age.saturating_add(1);
+            self.phase += phase_delta;
+            if self.phase >= 1.0 {
+                self.phase = self.phase.fract();
+                self.held_value = prng.gen_range(-1.0..=1.0);
+            }
+        }
+    }
+}
+
+/// The factor a gain or level is multiplied by for an LFO value in `[-1, 1]` and a depth in
+/// `[0, 1]`. The factor moves between `1 - depth` and 1, so the LFO only ever lowers the level.
+pub fn tremolo(value: f32, depth: f32) -> f32 {
+    1.0 - depth * (1.0 - value) * 0.5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::MAX_BLOCK_SIZE;
+
+    /// A low sample rate so every sample is a millisecond.
+    const SAMPLE_RATE: f32 = 1000.0;
+
+    fn settings(shape: LfoShape, delay_ms: f32, fade_ms: f32) -> LfoSettings {
+        LfoSettings {
+            shape,
+            rate_hz: 1.0,
+            delay_ms,
+            fade_ms,
+        }
+    }
+
+    fn render(lfo: &mut Lfo, settings: &LfoSettings, prng: &mut Pcg32) -> [f32; MAX_BLOCK_SIZE] {
+        let mut values = [0.0; MAX_BLOCK_SIZE];
+        lfo.next_block(&mut values, MAX_BLOCK_SIZE, settings, SAMPLE_RATE, prng);
+
+        values
+    }
+
+    #[test]
+    fn key_sync_restarts_the_phase() {
+        let mut prng = Pcg32::new(420, 1337);
+        let settings = settings(LfoShape::Saw, 0.0, 0.0);
+        let mut lfo = Lfo::default();
+        lfo.note_on(true, &mut prng);
+        let first_block = render(&mut lfo, &settings, &mut prng);
+        assert_eq!(first_block[0], -1.0);
+
+        // Without key sync the LFO continues where it left off
+        lfo.note_on(false, &mut prng);
+        let continued = render(&mut lfo, &settings, &mut prng);
+        assert!(continued[0] > first_block[MAX_BLOCK_SIZE - 1]);
+
+        lfo.note_on(true, &mut prng);
+        assert_eq!(render(&mut lfo, &settings, &mut prng), first_block);
+    }
+
+    #[test]
+    fn delay_silences_the_lfo_before_it_fades_in() {
+        let mut prng = Pcg32::new(420, 1337);
+        let settings = settings(LfoShape::Square, 10.0, 20.0);
+        let mut lfo = Lfo::default();
+        lfo.note_on(true, &mut prng);
+        let values = render(&mut lfo, &settings, &mut prng);
+
+        assert!(values[..=10].iter().all(|&value| value == 0.0));
+        assert_eq!(values[15], 0.25);
+        assert_eq!(values[20], 0.5);
+        assert!(values[30..].iter().all(|&value| value == 1.0));
+
+        // Notes restart the delay, even without key sync
+        lfo.note_on(false, &mut prng);
+        assert_eq!(render(&mut lfo, &settings, &mut prng)[5], 0.0);
+    }
+
+    #[test]
+    fn lfo_starts_immediately_after_the_delay_without_a_fade() {
+        let mut prng = Pcg32::new(420, 1337);
+        let settings = settings(LfoShape::Square, 10.0, 0.0);
+        let mut lfo = Lfo::default();
+        lfo.note_on(true, &mut prng);
+        let values = render(&mut lfo, &settings, &mut prng);
+
+        assert!(values[..10].iter().all(|&value| value == 0.0));
+        assert!(values[10..].iter().all(|&value| value == 1.0));
+    }
+}
@@ -1,28 +1,70 @@
+mod algorithm;
+mod dx7;
 mod editor;
 mod envelope;
+mod lfo;
+mod mod_matrix;
+mod operator;
 mod params;
+mod poly_mod;
+mod tempo;
+mod velocity;
 
+use algorithm::{Routing, RoutingBlock, RoutingSmoother};
+use dx7::Dx7SysEx;
 use editor::{FmSynthEditor, FmSynthEditorState, FmSynthEditorValues};
-use envelope::Envelope;
+use envelope::{Envelope, EnvelopeSettings, RetriggerMode};
+use lfo::{tremolo, Lfo, LfoMode, LfoSettings, NUM_LFOS};
+use mod_matrix::{
+    BlockModulation, ModMatrixBlock, ModSlot, ModSources, MOD_PITCH_RANGE, NUM_MOD_SLOTS,
+};
 use nih_plug::prelude::*;
 use nih_plug_iced::create_iced_editor;
+use operator::{
+    coarse_ratio, keyboard_level_scale, keyboard_rate_scale, operator_frequency, Operator,
+    Waveform, NUM_OPERATORS,
+};
 use params::FmSynthParams;
+use poly_mod::{PolyModBlock, PolyModTarget, VoicePolyMod};
 use rand::Rng;
 use rand_pcg::Pcg32;
-use std::{f32::consts, sync::Arc};
+use std::sync::{atomic::Ordering, Arc};
+use tempo::DEFAULT_TEMPO;
 
 /// The number of simultaneous voices for this synth.
 const NUM_VOICES: u32 = 16;
 
+/// Voices are terminated once they've been releasing for this long, even if their envelopes have
+/// not finished yet. This keeps very long releases from holding on to voices forever.
+const MAX_RELEASE_SECONDS: f32 = 60.0;
+
+/// The IDs of the amplitude envelope's parameters from before every operator got its own envelope,
+/// and the IDs of the first operator's envelope parameters they're restored to.
+const AMP_ENVELOPE_PARAM_IDS: [(&str, &str); 5] = [
+    ("amp_atk", "env_atk_1"),
+    ("amp_hol", "env_hol_1"),
+    ("amp_dec", "env_dec_1"),
+    ("amp_sus", "env_sus_1"),
+    ("amp_rel", "env_rel_1"),
+];
+
+/// How long a stolen voice takes to fade out, in milliseconds.
+const DECLICK_MS: f32 = 5.0;
+
+/// How long MIDI controller changes take to reach their new value, in milliseconds. This avoids
+/// zipper noise from the controllers' coarse steps.
+const CONTROLLER_SMOOTHING_MS: f32 = 10.0;
+
+/// The MIDI CCs the synth responds to.
+const MOD_WHEEL_CC: u8 = 1;
+const EXPRESSION_CC: u8 = 11;
+const SUSTAIN_PEDAL_CC: u8 = 64;
+const SOSTENUTO_PEDAL_CC: u8 = 66;
+
 /// The maximum size of an audio block. We'll split up the audio in blocks and render smoothed
 /// values to buffers since these values may need to be reused for multiple voices.
 const MAX_BLOCK_SIZE: usize = 64;
 
-// Polyphonic modulation works by assigning integer IDs to parameters. Pattern matching on these in
-// `PolyModulation` and `MonoAutomation` events makes it possible to easily link these events to the
-// correct parameter.
-pub const GAIN_POLY_MOD_ID: u32 = 0;
-
 /// A simple polyphonic synthesizer with support for CLAP's polyphonic modulation. See
 /// `NoteEvent::PolyModulation` for another source of information on how to use this.
 struct FmSynth {
@@ -33,11 +75,63 @@ struct FmSynth {
     /// A pseudo-random number generator. This will always be reseeded with the same seed when the
     /// synth is reset. That way the output is deterministic when rendering multiple times.
     prng: Pcg32,
+    /// Crossfades the operator routing when the algorithm changes. This is shared by all voices.
+    routing: RoutingSmoother,
+    /// The LFOs in global mode. These are shared by all voices. LFOs in per voice mode use the
+    /// voices' own LFOs instead.
+    lfos: [Lfo; NUM_LFOS],
+    /// The pitch bend wheel's position in `[-1, 1]`. This applies to all voices.
+    pitch_bend: Smoother<f32>,
+    /// The mod wheel's value in `[0, 1]`, used as a mod matrix source.
+    mod_wheel: Smoother<f32>,
+    /// The expression pedal's value in `[0, 1]`, used as a mod matrix source.
+    expression: Smoother<f32>,
+    /// Whether the sustain pedal is down. Voices whose notes end while it's down keep playing
+    /// until it comes up.
+    sustain_pedal: bool,
+    /// Whether the sostenuto pedal is down. See [`Voice::sostenuto`].
+    sostenuto_pedal: bool,
+    /// The channel pressure in `[0, 1]`. This is the aftertouch for voices that have not received
+    /// polyphonic aftertouch.
+    aftertouch: Smoother<f32>,
     /// The synth's voices. Inactive voices will be set to `None` values.
     voices: [Option<Voice>; NUM_VOICES as usize],
+    /// Voices that were stolen while they were still playing. These keep playing while they
+    /// quickly fade out to avoid clicks. The host has already been told that they've terminated.
+    stolen_voices: [Option<Voice>; NUM_VOICES as usize],
     /// The next internal voice ID, used only to figure out the oldest voice for voice stealing.
     /// This is incremented by one each time a voice is created.
     next_internal_voice_id: u64,
+    /// Scratch buffers for rendering a block. See [`BlockBuffers`].
+    buffers: BlockBuffers,
+}
+
+/// The buffers the smoothed values for a block are rendered to. These are too large to create on
+/// the stack for every block, so they're kept around and overwritten instead. The `voice_*` arrays
+/// are scratch arrays that an individual voice can use.
+struct BlockBuffers {
+    /// All parameters that can be polyphonically modulated, along with the values for the voice
+    /// that's currently being rendered.
+    poly_mod: PolyModBlock,
+    /// The mod matrix's modulation for the voice that's currently being rendered.
+    mod_matrix: ModMatrixBlock,
+    /// The operators' ratios and fixed frequencies for voices without frequency modulation.
+    ratios: [[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+    fixed_phase_deltas: [[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+    routing: RoutingBlock,
+    global_lfos: [[f32; MAX_BLOCK_SIZE]; NUM_LFOS],
+    pitch_bend: [f32; MAX_BLOCK_SIZE],
+    mod_wheel: [f32; MAX_BLOCK_SIZE],
+    expression: [f32; MAX_BLOCK_SIZE],
+    aftertouch: [f32; MAX_BLOCK_SIZE],
+    declick: [f32; MAX_BLOCK_SIZE],
+    voice_envelopes: [[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+    velocity_gain: [f32; MAX_BLOCK_SIZE],
+    level_scales: [[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+    pitch_envelope: [f32; MAX_BLOCK_SIZE],
+    voice_lfos: [[f32; MAX_BLOCK_SIZE]; NUM_LFOS],
+    voice_aftertouch: [f32; MAX_BLOCK_SIZE],
+    macro_amount: [f32; MAX_BLOCK_SIZE],
 }
 
 /// Data for a single synth voice. In a real synth where performance matter, you may want to use a
@@ -56,23 +150,53 @@ struct Voice {
     /// The voices internal ID. Each voice has an internal voice ID one higher than the previous
     /// voice. This is used to steal the last voice in case all 16 voices are in use.
     internal_voice_id: u64,
-    /// The square root of the note's velocity. This is used as a gain multiplier.
-    velocity_sqrt: f32,
+    /// The note's velocity, in `[0, 1]`. Used for the envelopes' velocity tracking on note off.
+    velocity: f32,
+    /// The note's velocity mapped through the velocity curve. This is used as a gain multiplier,
+    /// and it's smoothed so retriggering the voice with another velocity does not click.
+    velocity_gain: Smoother<f32>,
+    /// Whether the voice's note has ended and its envelopes are releasing.
+    releasing: bool,
+    /// Whether the voice's note has ended while the sustain or sostenuto pedal was down. The voice
+    /// keeps playing until the pedal comes up.
+    pedal_held: bool,
+    /// Whether the voice's key was down when the sostenuto pedal went down. Only these voices are
+    /// held by the sostenuto pedal.
+    sostenuto: bool,
+    /// How many samples the voice has been releasing for. See [`MAX_RELEASE_SECONDS`].
+    release_samples: u32,
+    /// Set when a note starts or retriggers the voice. The envelopes are started right before the
+    /// voice's next block is rendered, so polyphonic modulation sent on the same sample as the note
+    /// also applies to the parameters that are only read when the note starts.
+    envelope_start: Option<EnvelopeStart>,
 
-    /// The voice's current phase. This is randomized at the start of the voice
-    phase: f32,
-
-    /// The phase increment. This is based on the voice's frequency, derived from the note index.
-    /// Since we don't support pitch expressions or pitch bend, this value stays constant for the
-    /// duration of the voice.
+    /// The phase increment for an operator with a ratio of 1. This is based on the voice's
+    /// frequency, derived from the note index. This only changes when a legato note moves the
+    /// voice to another note. Pitch bend, vibrato and the pitch envelope are applied on top of this
+    /// for every sample.
     phase_delta: f32,
 
-    /// Fades between 0 and 1 with timings based on the global attack and release settings.
-    amp_envelope: Envelope<f32>,
+    /// Bends the pitch of the operators that have the pitch envelope enabled.
+    pitch_envelope: Envelope<f32>,
+    /// The voice's own LFOs. These are only used for the LFOs in per voice mode.
+    lfos: [Lfo; NUM_LFOS],
+    /// A random value in `[-1, 1]` picked when the voice starts, used as a mod matrix source.
+    random: f32,
+    /// The mod matrix's modulation from the end of the previous block, for the destinations that
+    /// are only read once per block.
+    block_mod: BlockModulation,
+
+    /// The voice's operators. Every operator's phase is randomized to the same value at the start
+    /// of the voice.
+    operators: [Operator; NUM_OPERATORS],
 
-    /// If this voice has polyphonic gain modulation applied, then this contains the normalized
-    /// offset and a smoother.
-    voice_gain: Option<(f32, Smoother<f32>)>,
+    /// The normalized offsets and smoothers for the parameters that have polyphonic modulation
+    /// applied to this voice.
+    poly_mod: VoicePolyMod,
+    /// The voice's polyphonic aftertouch, if it has received any.
+    aftertouch: Option<Smoother<f32>>,
+    /// Fades out the voice after it has been stolen.
+    declick: Option<Smoother<f32>>,
 }
 
 impl Default for FmSynth {
@@ -81,9 +205,44 @@ impl Default for FmSynth {
             params: Arc::default(),
             values: Arc::default(),
             prng: Pcg32::new(420, 1337),
+            routing: RoutingSmoother::default(),
+            lfos: Default::default(),
+            pitch_bend: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING_MS)),
+            mod_wheel: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING_MS)),
+            expression: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING_MS)),
+            sustain_pedal: false,
+            sostenuto_pedal: false,
+            aftertouch: Smoother::new(SmoothingStyle::Linear(CONTROLLER_SMOOTHING_MS)),
             // `[None; N]` requires the `Some(T)` to be `Copy`able
             voices: [0; NUM_VOICES as usize].map(|_| None),
+            stolen_voices: [0; NUM_VOICES as usize].map(|_| None),
             next_internal_voice_id: 0,
+            buffers: BlockBuffers::default(),
+        }
+    }
+}
+
+impl Default for BlockBuffers {
+    fn default() -> Self {
+        Self {
+            poly_mod: PolyModBlock::default(),
+            mod_matrix: ModMatrixBlock::default(),
+            ratios: [[0.0; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+            fixed_phase_deltas: [[0.0; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+            routing: RoutingBlock::default(),
+            global_lfos: [[0.0; MAX_BLOCK_SIZE]; NUM_LFOS],
+            pitch_bend: [0.0; MAX_BLOCK_SIZE],
+            mod_wheel: [0.0; MAX_BLOCK_SIZE],
+            expression: [0.0; MAX_BLOCK_SIZE],
+            aftertouch: [0.0; MAX_BLOCK_SIZE],
+            declick: [0.0; MAX_BLOCK_SIZE],
+            voice_envelopes: [[0.0; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+            velocity_gain: [0.0; MAX_BLOCK_SIZE],
+            level_scales: [[0.0; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+            pitch_envelope: [0.0; MAX_BLOCK_SIZE],
+            voice_lfos: [[0.0; MAX_BLOCK_SIZE]; NUM_LFOS],
+            voice_aftertouch: [0.0; MAX_BLOCK_SIZE],
+            macro_amount: [0.0; MAX_BLOCK_SIZE],
         }
     }
 }
@@ -102,25 +261,57 @@ impl Plugin for FmSynth {
         ..AudioIOLayout::const_default()
     }];
 
-    // We won't need any MIDI CCs here, we just want notes and polyphonic modulation
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    // MIDI CCs are needed for pitch bend, the mod wheel, the pedals and aftertouch
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
-    type SysExMessage = ();
-    type BackgroundTask = ();
+    type SysExMessage = Dx7SysEx;
+    // DX7 voices received over MIDI are applied on the GUI thread
+    type BackgroundTask = Dx7SysEx;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn filter_state(state: &mut PluginState) {
+        // Before every operator got its own envelope, the synth had a single amplitude envelope.
+        // Older projects restore that to the first operator's envelope.
+        for (old_id, new_id) in AMP_ENVELOPE_PARAM_IDS {
+            if let Some(value) = state.params.remove(old_id) {
+                state.params.entry(new_id.to_owned()).or_insert(value);
+            }
+        }
+    }
+
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = self.params.clone();
+        let values = self.values.clone();
+
+        Box::new(move |message| {
+            if let Ok(mut dx7_sysex) = values.dx7_sysex.lock() {
+                dx7_sysex.receive(message, &params);
+            }
+        })
+    }
+
     // If the synth as a variable number of voices, you will need to call
     // `context.set_current_voice_capacity()` in `initialize()` and in `process()` (when the
     // capacity changes) to inform the host about this.
     fn reset(&mut self) {
         // This ensures the output is at least somewhat deterministic when rendering to audio
         self.prng = Pcg32::new(420, 1337);
+        self.routing.reset(self.params.algorithm.value());
+        self.lfos = Default::default();
+        self.pitch_bend.reset(0.0);
+        self.mod_wheel.reset(0.0);
+        // Without an expression pedal, the expression is at its maximum
+        self.expression.reset(1.0);
+        self.sustain_pedal = false;
+        self.sostenuto_pedal = false;
+        self.aftertouch.reset(0.0);
 
         self.voices.fill(None);
+        self.stolen_voices.fill(None);
         self.next_internal_voice_id = 0;
     }
 
@@ -144,6 +335,7 @@ impl Plugin for FmSynth {
         // hand.
         let num_samples = buffer.samples();
         let sample_rate = context.transport().sample_rate;
+        let tempo = context.transport().tempo.unwrap_or(DEFAULT_TEMPO);
         let output = buffer.as_slice();
 
         let mut next_event = context.next_event();
@@ -165,16 +357,13 @@ impl Plugin for FmSynth {
             // has an internal note ID that's great than or equal to this one, then we should start
             // the note's smoother at the new value instead of fading in from the global value.
             let this_sample_internal_voice_id_start = self.next_internal_voice_id;
-            let hold = self.params.amp_hold_ms.value();
-            let decay = self.params.amp_decay_ms.value();
-            let sustain = self.params.amp_sustain_percentage.value() / 100.0;
 
             'events: loop {
                 match next_event {
                     // If the event happens now, then we'll keep processing events
                     Some(event) if (event.timing() as usize) <= block_start => {
-                        // This synth doesn't support any of the polyphonic expression events. A
-                        // real synth plugin however will want to support those.
+                        // Polyphonic aftertouch is the only polyphonic expression event this
+                        // synth supports. A real synth plugin will want to support the others too.
                         match event {
                             NoteEvent::NoteOn {
                                 timing,
@@ -184,21 +373,131 @@ impl Plugin for FmSynth {
                                 velocity,
                             } => {
                                 let initial_phase: f32 = self.prng.gen();
-                                let attack = self.params.amp_attack_ms.value();
-                                let voice =
-                                    self.start_voice(context, timing, voice_id, channel, note);
-                                voice.velocity_sqrt = velocity.sqrt();
-                                voice.phase = initial_phase;
+                                let velocity_curve = self.params.velocity_curve.value();
+                                // Global LFOs only restart their delay and fade in when no other
+                                // notes are held, since restarting them would also affect the
+                                // notes that are already playing. Per voice LFOs start at a random
+                                // phase unless they're key synced so the voices don't all move in
+                                // lockstep.
+                                let lfo_key_sync: [bool; NUM_LFOS] =
+                                    std::array::from_fn(|lfo_idx| {
+                                        self.params.lfos[lfo_idx].key_sync.value()
+                                    });
+                                let notes_held =
+                                    self.voices.iter().flatten().any(|voice| !voice.releasing);
+                                let mut new_voice_lfos: [Lfo; NUM_LFOS] = Default::default();
+                                for (lfo_idx, (lfo, voice_lfo)) in self
+                                    .lfos
+                                    .iter_mut()
+                                    .zip(new_voice_lfos.iter_mut())
+                                    .enumerate()
+                                {
+                                    if self.params.lfos[lfo_idx].mode.value() == LfoMode::Global
+                                        && !notes_held
+                                    {
+                                        lfo.note_on(lfo_key_sync[lfo_idx], &mut self.prng);
+                                    }
+                                    if !lfo_key_sync[lfo_idx] {
+                                        voice_lfo.randomize_phase(&mut self.prng);
+                                    }
+                                    voice_lfo.note_on(lfo_key_sync[lfo_idx], &mut self.prng);
+                                }
+                                let random: f32 = self.prng.gen_range(-1.0..=1.0);
+                                let retrigger = self.params.retrigger.value();
+                                let retriggered_voice_idx =
+                                    self.get_retrigger_voice_idx(retrigger, channel, note);
+                                // Velocity sensitivity and keyboard scaling are read when the
+                                // note starts. Retriggered voices that keep their voice ID also
+                                // keep their polyphonic modulation. Modulation for new voices is
+                                // sent after they've been created, so it's applied in
+                                // `PolyModulation`.
+                                let kept_poly_mod = retriggered_voice_idx
+                                    .and_then(|voice_idx| self.voices[voice_idx].as_ref())
+                                    .filter(|voice| {
+                                        voice.voice_id
+                                            == voice_id.unwrap_or_else(|| {
+                                                compute_fallback_voice_id(note, channel)
+                                            })
+                                    })
+                                    .map(|voice| &voice.poly_mod);
+                                let operator_scales: [(f32, f32); NUM_OPERATORS] =
+                                    std::array::from_fn(|op_idx| {
+                                        operator_scales(
+                                            &self.params,
+                                            op_idx,
+                                            note,
+                                            velocity,
+                                            |target| {
+                                                let param = self.params.poly_mod_param(target);
+                                                match kept_poly_mod {
+                                                    Some(poly_mod) => poly_mod.value(target, param),
+                                                    None => param.value(),
+                                                }
+                                            },
+                                        )
+                                    });
+                                let voice = match retriggered_voice_idx {
+                                    Some(voice_idx) => self.retrigger_voice(
+                                        context, timing, voice_idx, voice_id, channel, note,
+                                    ),
+                                    None => {
+                                        self.start_voice(context, timing, voice_id, channel, note)
+                                    }
+                                };
                                 voice.phase_delta = util::midi_note_to_freq(note) / sample_rate;
 
-                                // This starts with the attack portion of the amplitude envelope
-                                voice.amp_envelope.note_on(
-                                    sample_rate,
-                                    attack,
-                                    hold,
-                                    decay,
-                                    sustain,
-                                );
+                                // The envelopes start with their attack stages right before the
+                                // voice's next block is rendered, see `Voice::envelope_start`.
+                                // Rate scaling applies to all of the envelope's times. Retriggered
+                                // voices keep their phases so they don't click, and they smoothly
+                                // move to the new note's levels. Legato notes keep the envelopes
+                                // going with the first note's velocity and scaling.
+                                for (op_idx, operator) in voice.operators.iter_mut().enumerate() {
+                                    let (level_scale, rate_scale) = operator_scales[op_idx];
+                                    match (retriggered_voice_idx, retrigger) {
+                                        (None, _) => {
+                                            operator.level_scale.reset(level_scale);
+                                            operator.rate_scale = rate_scale;
+                                            operator.phase = initial_phase;
+                                        }
+                                        (Some(_), RetriggerMode::Analog) => {
+                                            operator
+                                                .level_scale
+                                                .set_target(sample_rate, level_scale);
+                                            operator.rate_scale = rate_scale;
+                                        }
+                                        (Some(_), _) => (),
+                                    }
+                                }
+                                match (retriggered_voice_idx, retrigger) {
+                                    (None, _) => {
+                                        voice.velocity = velocity;
+                                        voice.velocity_gain.reset(velocity_curve.apply(velocity));
+                                        voice.envelope_start = Some(EnvelopeStart::NoteOn);
+                                        voice.lfos = new_voice_lfos;
+                                        voice.random = random;
+                                    }
+                                    (Some(_), RetriggerMode::Analog) => {
+                                        voice.velocity = velocity;
+                                        voice.velocity_gain.set_target(
+                                            sample_rate,
+                                            velocity_curve.apply(velocity),
+                                        );
+                                        voice.envelope_start = Some(EnvelopeStart::Retrigger);
+                                        // LFOs that aren't key synced keep running
+                                        for ((lfo, voice_lfo), key_sync) in voice
+                                            .lfos
+                                            .iter_mut()
+                                            .zip(new_voice_lfos)
+                                            .zip(lfo_key_sync)
+                                        {
+                                            if key_sync {
+                                                *lfo = voice_lfo;
+                                            }
+                                        }
+                                    }
+                                    (Some(_), _) => (),
+                                }
                             }
                             NoteEvent::NoteOff {
                                 timing: _,
@@ -206,9 +505,13 @@ impl Plugin for FmSynth {
                                 channel,
                                 note,
                                 velocity: _,
-                            } => {
-                                self.start_release_for_voices(sample_rate, voice_id, channel, note)
-                            }
+                            } => self.start_release_for_voices(
+                                tempo,
+                                sample_rate,
+                                voice_id,
+                                channel,
+                                note,
+                            ),
                             NoteEvent::Choke {
                                 timing,
                                 voice_id,
@@ -230,47 +533,39 @@ impl Plugin for FmSynth {
                                 // it has been terminated (because the host doesn't know that it
                                 // will be). Because of that, we won't print any assertion failures
                                 // when we can't find the voice index here.
-                                if let Some(voice_idx) = self.get_voice_idx(voice_id) {
-                                    let voice = self.voices[voice_idx].as_mut().unwrap();
-
-                                    match poly_modulation_id {
-                                        GAIN_POLY_MOD_ID => {
-                                            // This should either create a smoother for this
-                                            // modulated parameter or update the existing one.
-                                            // Notice how this uses the parameter's unmodulated
-                                            // normalized value in combination with the normalized
-                                            // offset to create the target plain value
-                                            let target_plain_value = self
-                                                .params
-                                                .gain
-                                                .preview_modulated(normalized_offset);
-                                            let (_, smoother) =
-                                                voice.voice_gain.get_or_insert_with(|| {
-                                                    (
-                                                        normalized_offset,
-                                                        self.params.gain.smoothed.clone(),
-                                                    )
-                                                });
-
-                                            // If this `PolyModulation` events happens on the
-                                            // same sample as a voice's `NoteOn` event, then it
-                                            // should immediately use the modulated value
-                                            // instead of slowly fading in
-                                            if voice.internal_voice_id
-                                                >= this_sample_internal_voice_id_start
-                                            {
-                                                smoother.reset(target_plain_value);
-                                            } else {
-                                                smoother
-                                                    .set_target(sample_rate, target_plain_value);
+                                match PolyModTarget::from_id(poly_modulation_id) {
+                                    Some(target) => {
+                                        if let Some(voice_idx) = self.get_voice_idx(voice_id) {
+                                            let voice = self.voices[voice_idx].as_mut().unwrap();
+
+                                            // If this `PolyModulation` events happens on the same
+                                            // sample as a voice's `NoteOn` event, then it should
+                                            // immediately use the modulated value instead of
+                                            // slowly fading in
+                                            let reset = voice.internal_voice_id
+                                                >= this_sample_internal_voice_id_start;
+                                            voice.poly_mod.modulate(
+                                                target,
+                                                self.params.poly_mod_param(target),
+                                                normalized_offset,
+                                                sample_rate,
+                                                reset,
+                                            );
+                                            if let Some(op_idx) = target.note_on_operator() {
+                                                voice.update_operator_scales(
+                                                    &self.params,
+                                                    op_idx,
+                                                    sample_rate,
+                                                    reset,
+                                                );
                                             }
                                         }
-                                        n => nih_debug_assert_failure!(
-                                            "Polyphonic modulation sent for unknown poly \
-                                             modulation ID {}",
-                                            n
-                                        ),
                                     }
+                                    None => nih_debug_assert_failure!(
+                                        "Polyphonic modulation sent for unknown poly modulation \
+                                         ID {}",
+                                        poly_modulation_id
+                                    ),
                                 }
                             }
                             NoteEvent::MonoAutomation {
@@ -282,33 +577,110 @@ impl Plugin for FmSynth {
                                 // automated value. So if the host sends a new automation value for
                                 // a modulated parameter, the modulated values/smoothing targets
                                 // need to be updated for all polyphonically modulated voices.
-                                for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
-                                    match poly_modulation_id {
-                                        GAIN_POLY_MOD_ID => {
-                                            let (normalized_offset, smoother) =
-                                                match voice.voice_gain.as_mut() {
-                                                    Some((o, s)) => (o, s),
-                                                    // If the voice does not have existing
-                                                    // polyphonic modulation, then there's nothing
-                                                    // to do here. The global automation/monophonic
-                                                    // modulation has already been taken care of by
-                                                    // the framework.
-                                                    None => continue,
-                                                };
-                                            let target_plain_value =
-                                                self.params.gain.preview_plain(
-                                                    normalized_value + *normalized_offset,
-                                                );
-                                            smoother.set_target(sample_rate, target_plain_value);
+                                match PolyModTarget::from_id(poly_modulation_id) {
+                                    Some(target) => {
+                                        let param = self.params.poly_mod_param(target);
+                                        for voice in
+                                            self.voices.iter_mut().filter_map(|v| v.as_mut())
+                                        {
+                                            voice.poly_mod.automate(
+                                                target,
+                                                param,
+                                                normalized_value,
+                                                sample_rate,
+                                            );
+                                            // Voices without modulation keep the values
+                                            // from when their notes started
+                                            match target.note_on_operator() {
+                                                Some(op_idx)
+                                                    if voice.poly_mod.is_modulated(target) =>
+                                                {
+                                                    voice.update_operator_scales(
+                                                        &self.params,
+                                                        op_idx,
+                                                        sample_rate,
+                                                        false,
+                                                    )
+                                                }
+                                                _ => (),
+                                            }
                                         }
-                                        n => nih_debug_assert_failure!(
-                                            "Automation event sent for unknown poly modulation ID \
-                                             {}",
-                                            n
-                                        ),
                                     }
+                                    None => nih_debug_assert_failure!(
+                                        "Automation event sent for unknown poly modulation ID {}",
+                                        poly_modulation_id
+                                    ),
                                 }
                             }
+                            NoteEvent::MidiPitchBend {
+                                timing: _,
+                                channel: _,
+                                value,
+                            } => self.pitch_bend.set_target(sample_rate, value * 2.0 - 1.0),
+                            NoteEvent::MidiCC {
+                                timing: _,
+                                channel: _,
+                                cc,
+                                value,
+                            } => match cc {
+                                MOD_WHEEL_CC => self.mod_wheel.set_target(sample_rate, value),
+                                EXPRESSION_CC => self.expression.set_target(sample_rate, value),
+                                // Pedals are down for values of 64 and up
+                                SUSTAIN_PEDAL_CC => {
+                                    self.sustain_pedal = value >= 0.5;
+                                    self.release_pedal_held_voices(tempo, sample_rate);
+                                }
+                                SOSTENUTO_PEDAL_CC => {
+                                    // The sostenuto pedal only holds the notes whose keys are
+                                    // down at the moment the pedal goes down
+                                    let sostenuto_pedal = value >= 0.5;
+                                    if sostenuto_pedal != self.sostenuto_pedal {
+                                        self.sostenuto_pedal = sostenuto_pedal;
+                                        for voice in
+                                            self.voices.iter_mut().filter_map(|v| v.as_mut())
+                                        {
+                                            voice.sostenuto = sostenuto_pedal
+                                                && !voice.releasing
+                                                && !voice.pedal_held;
+                                        }
+                                        self.release_pedal_held_voices(tempo, sample_rate);
+                                    }
+                                }
+                                _ => (),
+                            },
+                            NoteEvent::MidiChannelPressure {
+                                timing: _,
+                                channel: _,
+                                pressure,
+                            } => self.aftertouch.set_target(sample_rate, pressure),
+                            NoteEvent::PolyPressure {
+                                timing: _,
+                                voice_id,
+                                channel,
+                                note,
+                                pressure,
+                            } => {
+                                for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
+                                    if voice_id == Some(voice.voice_id)
+                                        || (channel == voice.channel && note == voice.note)
+                                    {
+                                        // The first polyphonic aftertouch message for a voice
+                                        // jumps straight to its value
+                                        let smoother = voice.aftertouch.get_or_insert_with(|| {
+                                            let smoother = Smoother::new(SmoothingStyle::Linear(
+                                                CONTROLLER_SMOOTHING_MS,
+                                            ));
+                                            smoother.reset(pressure);
+                                            smoother
+                                        });
+                                        smoother.set_target(sample_rate, pressure);
+                                    }
+                                }
+                            }
+                            NoteEvent::MidiSysEx { timing: _, message } => {
+                                // Parameters can only be changed from the GUI thread
+                                context.execute_gui(message);
+                            }
                             _ => (),
                         };
 
@@ -332,74 +704,403 @@ impl Plugin for FmSynth {
             // have polyphonic modulation applied to them. With a plugin as simple as this it would
             // be possible to avoid this completely by simply always copying the smoother into the
             // voice's struct, but that may not be realistic when the plugin has hundreds of
-            // parameters. The `voice_*` arrays are scratch arrays that an individual voice can use.
+            // parameters. See `BlockBuffers` for the arrays the values are rendered to.
             let block_len = block_end - block_start;
-            let mut gain = [0.0; MAX_BLOCK_SIZE];
-            let mut voice_gain = [0.0; MAX_BLOCK_SIZE];
-            let mut voice_amp_envelope = [0.0; MAX_BLOCK_SIZE];
-            self.params.gain.smoothed.next_block(&mut gain, block_len);
+            let BlockBuffers {
+                poly_mod,
+                mod_matrix,
+                ratios,
+                fixed_phase_deltas,
+                routing,
+                global_lfos,
+                pitch_bend,
+                mod_wheel,
+                expression,
+                aftertouch,
+                declick,
+                voice_envelopes,
+                velocity_gain,
+                level_scales,
+                pitch_envelope,
+                voice_lfos,
+                voice_aftertouch,
+                macro_amount,
+            } = &mut self.buffers;
+            let unity_gain = [1.0; MAX_BLOCK_SIZE];
+            let coarse_ratios: [f32; NUM_OPERATORS] = std::array::from_fn(|op_idx| {
+                coarse_ratio(self.params.operators[op_idx].coarse.value())
+            });
+            let fixed: [bool; NUM_OPERATORS] =
+                std::array::from_fn(|op_idx| self.params.operators[op_idx].fixed.value());
+            let pitch_envelope_enabled: [bool; NUM_OPERATORS] =
+                std::array::from_fn(|op_idx| self.params.operators[op_idx].pitch_envelope.value());
+            let waveforms: [Waveform; NUM_OPERATORS] =
+                std::array::from_fn(|op_idx| self.params.operators[op_idx].waveform.value());
+            let lfo_settings: [LfoSettings; NUM_LFOS] =
+                std::array::from_fn(|lfo_idx| self.params.lfos[lfo_idx].settings(tempo));
+            let lfo_modes: [LfoMode; NUM_LFOS] =
+                std::array::from_fn(|lfo_idx| self.params.lfos[lfo_idx].mode.value());
+            let mod_slots: [ModSlot; NUM_MOD_SLOTS] =
+                std::array::from_fn(|slot_idx| self.params.mod_slots[slot_idx].slot());
+            poly_mod.next_block(&self.params, block_len);
+            self.pitch_bend.next_block(pitch_bend, block_len);
+            let pitch_bend_up = self.params.pitch_bend_up.value() as f32;
+            let pitch_bend_down = self.params.pitch_bend_down.value() as f32;
+            for pitch_bend in &mut pitch_bend[..block_len] {
+                *pitch_bend = pitch_bend_semitones(*pitch_bend, pitch_bend_up, pitch_bend_down);
+            }
+            self.mod_wheel.next_block(mod_wheel, block_len);
+            self.expression.next_block(expression, block_len);
+            self.aftertouch.next_block(aftertouch, block_len);
+            self.routing
+                .set_algorithm(sample_rate, self.params.algorithm.value());
+            self.routing.next_block(routing, block_len);
+            // Global LFOs keep running even when no voices are playing
+            for (lfo_idx, lfo) in self.lfos.iter_mut().enumerate() {
+                if lfo_modes[lfo_idx] == LfoMode::Global {
+                    lfo.next_block(
+                        &mut global_lfos[lfo_idx],
+                        block_len,
+                        &lfo_settings[lfo_idx],
+                        sample_rate,
+                        &mut self.prng,
+                    );
+                }
+            }
+            for op_idx in 0..NUM_OPERATORS {
+                // An operator's frequency is either a ratio of the voice's frequency, or a fixed
+                // frequency that does not depend on the note. Only one of the two arrays will
+                // contain non-zero values, so the voices can simply add them together. Voices with
+                // modulation for these parameters compute their own frequencies.
+                for value_idx in 0..block_len {
+                    (
+                        ratios[op_idx][value_idx],
+                        fixed_phase_deltas[op_idx][value_idx],
+                    ) = operator_frequency(
+                        coarse_ratios[op_idx],
+                        poly_mod.get(PolyModTarget::Fine(op_idx), value_idx),
+                        poly_mod.get(PolyModTarget::Detune(op_idx), value_idx),
+                        fixed[op_idx],
+                        poly_mod.get(PolyModTarget::FixedFrequency(op_idx), value_idx),
+                        sample_rate,
+                    );
+                }
+            }
 
             // TODO: Some form of band limiting
             // TODO: Filter
-            for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
+            for voice in self
+                .voices
+                .iter_mut()
+                .chain(self.stolen_voices.iter_mut())
+                .filter_map(|v| v.as_mut())
+            {
                 // Depending on whether the voice has polyphonic modulation applied to it,
                 // either the global parameter values are used, or the voice's smoother is used
                 // to generate unique modulated values for that voice
-                let gain = match &voice.voice_gain {
-                    Some((_, smoother)) => {
-                        smoother.next_block(&mut voice_gain, block_len);
-                        &voice_gain
+                poly_mod.next_voice_block(&voice.poly_mod, block_len);
+                voice.start_envelopes(&self.params, tempo, sample_rate);
+                let declick = match &voice.declick {
+                    Some(smoother) => {
+                        smoother.next_block(declick, block_len);
+                        &*declick
                     }
-                    None => &gain,
+                    None => &unity_gain,
                 };
 
-                // This is an exponential smoother repurposed as an ADSR envelope with values between
-                // 0 and 1. When a note off event is received, this envelope will start fading out
-                // again. When it reaches 0, we will terminate the voice.
+                // The operators' envelopes have values between 0 and 1. When a note off event is
+                // received, these envelopes will start fading out again. When the carriers' envelopes
+                // reach 0, we will terminate the voice. Changes to the decay, sustain and release
+                // parameters also affect notes that are already playing.
+                for (op_idx, (((operator, operator_params), voice_envelope), level_scale)) in voice
+                    .operators
+                    .iter_mut()
+                    .zip(self.params.operators.iter())
+                    .zip(voice_envelopes.iter_mut())
+                    .zip(level_scales.iter_mut())
+                    .enumerate()
+                {
+                    let envelope_params = &operator_params.envelope;
+                    let mut envelope_values = poly_mod
+                        .envelope_values(|target| PolyModTarget::OperatorEnvelope(op_idx, target));
+                    voice
+                        .block_mod
+                        .apply_envelope(op_idx, envelope_params, &mut envelope_values);
+                    operator.envelope.update(
+                        &envelope_params
+                            .settings(tempo, voice.velocity, voice.note, &envelope_values)
+                            .scale_durations(operator.rate_scale),
+                    );
+                    operator.envelope.next_block(voice_envelope, block_len);
+                    operator.level_scale.next_block(level_scale, block_len);
+                }
+                voice.velocity_gain.next_block(velocity_gain, block_len);
                 voice
-                    .amp_envelope
-                    .next_block(&mut voice_amp_envelope, block_len);
+                    .pitch_envelope
+                    .update(&self.params.pitch_envelope.envelope.settings(
+                        tempo,
+                        voice.velocity,
+                        voice.note,
+                        &poly_mod.envelope_values(PolyModTarget::PitchEnvelope),
+                    ));
+                voice.pitch_envelope.next_block(pitch_envelope, block_len);
+                for (lfo_idx, lfo) in voice.lfos.iter_mut().enumerate() {
+                    if lfo_modes[lfo_idx] == LfoMode::PerVoice {
+                        let mut settings = lfo_settings[lfo_idx];
+                        poly_mod.apply_lfo(
+                            lfo_idx,
+                            self.params.lfos[lfo_idx].sync.value(),
+                            &mut settings,
+                        );
+                        voice.block_mod.apply_lfo(
+                            lfo_idx,
+                            &self.params.lfos[lfo_idx],
+                            &mut settings,
+                        );
+                        lfo.next_block(
+                            &mut voice_lfos[lfo_idx],
+                            block_len,
+                            &settings,
+                            sample_rate,
+                            &mut self.prng,
+                        );
+                    }
+                }
+                let lfos: [&[f32; MAX_BLOCK_SIZE]; NUM_LFOS] =
+                    std::array::from_fn(|lfo_idx| match lfo_modes[lfo_idx] {
+                        LfoMode::Global => &global_lfos[lfo_idx],
+                        LfoMode::PerVoice => &voice_lfos[lfo_idx],
+                    });
+                let aftertouch = match &voice.aftertouch {
+                    Some(smoother) => {
+                        smoother.next_block(voice_aftertouch, block_len);
+                        &*voice_aftertouch
+                    }
+                    None => &*aftertouch,
+                };
+                if voice.releasing {
+                    voice.release_samples += block_len as u32;
+                }
+
+                // The mod matrix is evaluated once per block for every voice. Destinations that
+                // none of the slots modulate are skipped below.
+                mod_matrix.evaluate(
+                    &mod_slots,
+                    |slot_idx| poly_mod.block(PolyModTarget::ModSlotDepth(slot_idx)),
+                    ModSources {
+                        lfos,
+                        pitch_envelope,
+                        envelopes: voice_envelopes,
+                        velocity: voice.velocity,
+                        note: voice.note,
+                        mod_wheel,
+                        expression,
+                        aftertouch,
+                        random: voice.random,
+                        macro_amount: poly_mod.block(PolyModTarget::Macro),
+                    },
+                    &self.params.macro_amount,
+                    macro_amount,
+                    block_len,
+                );
+                voice.block_mod = mod_matrix.block_modulation(block_len);
 
                 // All samples within a block.
                 for (value_idx, sample_idx) in (block_start..block_end).enumerate() {
-                    let amp = voice.velocity_sqrt * gain[value_idx] * voice_amp_envelope[value_idx];
+                    let mut lfo_pitch = 0.0;
+                    let mut lfo_gain = 1.0;
+                    for (lfo_idx, (lfo, lfo_params)) in
+                        lfos.iter().zip(self.params.lfos.iter()).enumerate()
+                    {
+                        let pitch_depth = mod_matrix.lfo_pitch_depths[lfo_idx].apply(
+                            &lfo_params.pitch_depth,
+                            value_idx,
+                            poly_mod.get(PolyModTarget::LfoPitchDepth(lfo_idx), value_idx),
+                        );
+                        let gain_depth = mod_matrix.lfo_gain_depths[lfo_idx].apply(
+                            &lfo_params.gain_depth,
+                            value_idx,
+                            poly_mod.get(PolyModTarget::LfoGainDepth(lfo_idx), value_idx),
+                        );
+                        lfo_pitch += pitch_depth * lfo[value_idx];
+                        lfo_gain *= tremolo(lfo[value_idx], gain_depth);
+                    }
+                    let amp = velocity_gain[value_idx]
+                        * mod_matrix.gain.apply(
+                            &self.params.gain,
+                            value_idx,
+                            poly_mod.get(PolyModTarget::Gain, value_idx),
+                        )
+                        * declick[value_idx]
+                        * lfo_gain;
+                    // Pitch bend, LFO vibrato and the mod matrix's pitch destination only apply to
+                    // the voice's frequency, so fixed frequency operators are left alone
+                    let voice_phase_delta = voice.phase_delta
+                        * 2.0f32.powf(
+                            (pitch_bend[value_idx]
+                                + lfo_pitch
+                                + mod_matrix.pitch.get(value_idx) * MOD_PITCH_RANGE)
+                                / 12.0,
+                        );
+                    // The pitch envelope multiplies the phase increments of the operators it's
+                    // enabled for, including fixed frequency operators
+                    let pitch_depth = mod_matrix.pitch_envelope_depth.apply(
+                        &self.params.pitch_envelope.depth,
+                        value_idx,
+                        poly_mod.get(PolyModTarget::PitchEnvelopeDepth, value_idx),
+                    );
+                    let pitch_ratio = 2.0f32.powf(pitch_depth * pitch_envelope[value_idx] / 12.0);
 
-                    // Linearly scales a number from [0, 1] to [-3, 1]
-                    // let sample = (voice.phase * 2.0 - 1.0) * amp;
+                    // The operators are evaluated from the last to the first. In every algorithm
+                    // modulators come after the operators they modulate, so their outputs have
+                    // already been computed for this sample. The modulation matrix can also
+                    // create cycles. Modulators that have not been evaluated yet contribute their
+                    // output from the previous sample instead. An operator modulating itself is
+                    // treated as feedback. The carriers are summed into the voice's output. The
+                    // modulation matrix is added on top of the algorithm's routing.
+                    let mut sample = 0.0;
+                    for op_idx in (0..NUM_OPERATORS).rev() {
+                        let operator_params = &self.params.operators[op_idx];
+                        let modulation_depth = |modulator_idx: usize| {
+                            routing.modulation[op_idx][modulator_idx][value_idx]
+                                + mod_matrix.modulations[op_idx][modulator_idx].apply(
+                                    &operator_params.modulation[modulator_idx].depth,
+                                    value_idx,
+                                    poly_mod.get(
+                                        PolyModTarget::Modulation(op_idx, modulator_idx),
+                                        value_idx,
+                                    ),
+                                )
+                        };
+                        let modulation: f32 = voice
+                            .operators
+                            .iter()
+                            .enumerate()
+                            .filter(|(modulator_idx, _)| *modulator_idx != op_idx)
+                            .map(|(modulator_idx, modulator)| {
+                                modulator.output * modulation_depth(modulator_idx)
+                            })
+                            .sum();
+                        let feedback = mod_matrix.feedbacks[op_idx].apply(
+                            &operator_params.feedback,
+                            value_idx,
+                            poly_mod.get(PolyModTarget::Feedback(op_idx), value_idx),
+                        ) + modulation_depth(op_idx);
+
+                        let (ratio, fixed_phase_delta) = if mod_matrix.modulates_frequency(op_idx)
+                            || poly_mod.modulates_frequency(op_idx)
+                        {
+                            operator_frequency(
+                                coarse_ratios[op_idx],
+                                mod_matrix.fines[op_idx].apply(
+                                    &operator_params.fine,
+                                    value_idx,
+                                    poly_mod.get(PolyModTarget::Fine(op_idx), value_idx),
+                                ),
+                                mod_matrix.detunes[op_idx].apply(
+                                    &operator_params.detune_cents,
+                                    value_idx,
+                                    poly_mod.get(PolyModTarget::Detune(op_idx), value_idx),
+                                ),
+                                fixed[op_idx],
+                                mod_matrix.fixed_frequencies[op_idx].apply(
+                                    &operator_params.fixed_frequency,
+                                    value_idx,
+                                    poly_mod.get(PolyModTarget::FixedFrequency(op_idx), value_idx),
+                                ),
+                                sample_rate,
+                            )
+                        } else {
+                            (
+                                ratios[op_idx][value_idx],
+                                fixed_phase_deltas[op_idx][value_idx],
+                            )
+                        };
+                        let phase_delta = voice_phase_delta * ratio + fixed_phase_delta;
+                        let phase_delta = if pitch_envelope_enabled[op_idx] {
+                            phase_delta * pitch_ratio
+                        } else {
+                            phase_delta
+                        };
 
-                    // Sine wave generator
-                    let sample = (voice.phase * consts::TAU).sin() * amp;
+                        let lfo_level: f32 = lfos
+                            .iter()
+                            .enumerate()
+                            .map(|(lfo_idx, lfo)| {
+                                tremolo(
+                                    lfo[value_idx],
+                                    mod_matrix.lfo_level_depths[lfo_idx][op_idx].apply(
+                                        &self.params.lfos[lfo_idx].level_depths[op_idx].depth,
+                                        value_idx,
+                                        poly_mod.get(
+                                            PolyModTarget::LfoLevelDepth(lfo_idx, op_idx),
+                                            value_idx,
+                                        ),
+                                    ),
+                                )
+                            })
+                            .product();
 
-                    voice.phase += voice.phase_delta;
-                    if voice.phase >= 1.0 {
-                        voice.phase -= 1.0;
+                        let operator_sample = voice.operators[op_idx].next_sample(
+                            waveforms[op_idx],
+                            phase_delta,
+                            modulation,
+                            feedback,
+                            mod_matrix.levels[op_idx].apply(
+                                &operator_params.level,
+                                value_idx,
+                                poly_mod.get(PolyModTarget::Level(op_idx), value_idx),
+                            ) * level_scales[op_idx][value_idx]
+                                * voice_envelopes[op_idx][value_idx]
+                                * lfo_level,
+                        );
+                        sample += operator_sample * routing.carriers[op_idx][value_idx];
                     }
+                    let sample = sample * amp;
 
                     output[0][sample_idx] += sample;
                     output[1][sample_idx] += sample;
-
-                    voice
-                        .amp_envelope
-                        .next_phase(sample_rate, hold, decay, sustain);
                 }
             }
 
             // Terminate voices whose release period has fully ended. This could be done as part of
-            // the previous loop but this is simpler.
-            for voice in self.voices.iter_mut() {
-                match voice {
-                    Some(v) if v.amp_envelope.is_released() => {
-                        // This event is very important, as it allows the host to manage its own modulation
-                        // voices
-                        context.send_event(NoteEvent::VoiceTerminated {
-                            timing: block_end as u32,
-                            voice_id: Some(v.voice_id),
-                            channel: v.channel,
-                            note: v.note,
-                        });
+            // the previous loop but this is simpler. Voices that have released but would still be
+            // audible are faded out like stolen voices instead of being cut off. Voices that have
+            // been releasing for too long are terminated regardless.
+            let algorithm_routing = self.params.algorithm.value().routing();
+            let max_release_samples = (MAX_RELEASE_SECONDS * sample_rate) as u32;
+            for voice_idx in 0..self.voices.len() {
+                let voice_end = self.voices[voice_idx]
+                    .as_ref()
+                    .and_then(|voice| voice.end(algorithm_routing, max_release_samples));
+
+                if let Some(voice_end) = voice_end {
+                    let voice = self.voices[voice_idx].take().unwrap();
+                    // This event is very important, as it allows the host to manage its own modulation
+                    // voices
+                    context.send_event(NoteEvent::VoiceTerminated {
+                        timing: block_end as u32,
+                        voice_id: Some(voice.voice_id),
+                        channel: voice.channel,
+                        note: voice.note,
+                    });
+                    if voice_end == VoiceEnd::FadeOut {
+                        self.fade_out_voice(voice, sample_rate);
+                    }
+                }
+            }
+
+            // Stolen voices have already been terminated, so they can be removed silently once
+            // they've faded out
+            for voice in self.stolen_voices.iter_mut() {
+                if let Some(Voice {
+                    declick: Some(declick),
+                    ..
+                }) = voice
+                {
+                    if !declick.is_smoothing() {
                         *voice = None;
                     }
-                    _ => (),
                 }
             }
 
@@ -433,6 +1134,10 @@ impl Plugin for FmSynth {
             block_end = (block_start + MAX_BLOCK_SIZE).min(num_samples);
         }
 
+        self.values
+            .active_voices
+            .store(self.active_voice_count(), Ordering::Relaxed);
+
         ProcessStatus::Normal
     }
 }
@@ -456,17 +1161,12 @@ impl FmSynth {
         channel: u8,
         note: u8,
     ) -> &mut Voice {
-        let new_voice = Voice {
-            voice_id: voice_id.unwrap_or_else(|| compute_fallback_voice_id(note, channel)),
-            internal_voice_id: self.next_internal_voice_id,
+        let new_voice = Voice::new(
+            voice_id.unwrap_or_else(|| compute_fallback_voice_id(note, channel)),
+            self.next_internal_voice_id,
             channel,
             note,
-            velocity_sqrt: 1.0,
-            phase: 0.0,
-            phase_delta: 0.0,
-            amp_envelope: Envelope::default(),
-            voice_gain: None,
-        };
+        );
         self.next_internal_voice_id = self.next_internal_voice_id.wrapping_add(1);
 
         // Can't use `.iter_mut().find()` here because nonlexical lifetimes don't apply to return
@@ -480,65 +1180,179 @@ impl FmSynth {
                 // If there is no free voice, find and steal the oldest one
                 // SAFETY: We can skip a lot of checked unwraps here since we already know all voices are in
                 //         use
-                let oldest_voice = unsafe {
+                let oldest_voice_idx = unsafe {
                     self.voices
-                        .iter_mut()
-                        .min_by_key(|voice| voice.as_ref().unwrap_unchecked().internal_voice_id)
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, voice)| {
+                            voice.as_ref().unwrap_unchecked().internal_voice_id
+                        })
                         .unwrap_unchecked()
+                        .0
                 };
 
                 // The stolen voice needs to be terminated so the host can reuse its modulation
                 // resources
-                {
-                    let oldest_voice = oldest_voice.as_ref().unwrap();
-                    context.send_event(NoteEvent::VoiceTerminated {
-                        timing: sample_offset,
-                        voice_id: Some(oldest_voice.voice_id),
-                        channel: oldest_voice.channel,
-                        note: oldest_voice.note,
-                    });
-                }
+                let stolen_voice = self.voices[oldest_voice_idx].replace(new_voice).unwrap();
+                context.send_event(NoteEvent::VoiceTerminated {
+                    timing: sample_offset,
+                    voice_id: Some(stolen_voice.voice_id),
+                    channel: stolen_voice.channel,
+                    note: stolen_voice.note,
+                });
+
+                // Instead of cutting it off, the stolen voice is faded out
+                self.fade_out_voice(stolen_voice, context.transport().sample_rate);
 
-                *oldest_voice = Some(new_voice);
-                return oldest_voice.as_mut().unwrap();
+                return self.voices[oldest_voice_idx].as_mut().unwrap();
             }
         }
     }
 
+    /// Quickly fade out a voice that has been removed from `voices` instead of cutting it off. The
+    /// host must already have been told that the voice has terminated. If every stolen voice slot
+    /// is in use, then the stolen voice that has faded out the furthest gets replaced.
+    fn fade_out_voice(&mut self, mut voice: Voice, sample_rate: f32) {
+        let declick = Smoother::new(SmoothingStyle::Linear(DECLICK_MS));
+        declick.reset(1.0);
+        declick.set_target(sample_rate, 0.0);
+        voice.declick = Some(declick);
+
+        let declick_gain = |voice: &Option<Voice>| {
+            voice
+                .as_ref()
+                .and_then(|voice| voice.declick.as_ref())
+                .map_or(0.0, |declick| declick.previous_value())
+        };
+        let slot_idx = self
+            .stolen_voices
+            .iter()
+            .position(|voice| voice.is_none())
+            .or_else(|| {
+                self.stolen_voices
+                    .iter()
+                    .enumerate()
+                    .min_by(|(_, a), (_, b)| declick_gain(a).total_cmp(&declick_gain(b)))
+                    .map(|(slot_idx, _)| slot_idx)
+            });
+        if let Some(slot_idx) = slot_idx {
+            self.stolen_voices[slot_idx] = Some(voice);
+        }
+    }
+
     /// Start the release process for one or more voice by changing their amplitude envelope. If
-    /// `voice_id` is not provided, then this will terminate all matching voices.
+    /// `voice_id` is not provided, then this will terminate all matching voices. Voices held by
+    /// the sustain or sostenuto pedal keep playing until the pedal comes up.
     fn start_release_for_voices(
         &mut self,
+        tempo: f64,
         sample_rate: f32,
         voice_id: Option<i32>,
         channel: u8,
         note: u8,
     ) {
-        for voice in self.voices.iter_mut() {
-            match voice {
-                Some(Voice {
-                    voice_id: candidate_voice_id,
-                    channel: candidate_channel,
-                    note: candidate_note,
-                    amp_envelope,
-                    ..
-                }) if voice_id == Some(*candidate_voice_id)
-                    || (channel == *candidate_channel && note == *candidate_note) =>
-                {
-                    amp_envelope.note_off(sample_rate, self.params.amp_release_ms.value());
+        for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
+            if voice_id == Some(voice.voice_id) || (channel == voice.channel && note == voice.note)
+            {
+                if self.sustain_pedal || voice.sostenuto {
+                    voice.pedal_held = true;
+                } else {
+                    voice.start_release(&self.params, tempo, sample_rate);
+                }
 
-                    // If this targetted a single voice ID, we're done here. Otherwise there may be
-                    // multiple overlapping voices as we enabled support for that in the
-                    // `PolyModulationConfig`.
-                    if voice_id.is_some() {
-                        return;
-                    }
+                // If this targetted a single voice ID, we're done here. Otherwise there may be
+                // multiple overlapping voices as we enabled support for that in the
+                // `PolyModulationConfig`.
+                if voice_id.is_some() {
+                    return;
                 }
-                _ => (),
             }
         }
     }
 
+    /// Release the voices whose notes have ended while they were held by a pedal that has now
+    /// come up.
+    fn release_pedal_held_voices(&mut self, tempo: f64, sample_rate: f32) {
+        for voice in self.voices.iter_mut().filter_map(|v| v.as_mut()) {
+            if voice.pedal_held && !self.sustain_pedal && !voice.sostenuto {
+                voice.start_release(&self.params, tempo, sample_rate);
+            }
+        }
+    }
+
+    /// Find the voice a new note should reuse instead of starting a new voice. With analog
+    /// retriggering this is a voice for the same note, and with legato this is the most recent
+    /// voice on the same channel whose key is still down. Voices that are only held by a pedal
+    /// don't count for legato.
+    fn get_retrigger_voice_idx(
+        &self,
+        retrigger: RetriggerMode,
+        channel: u8,
+        note: u8,
+    ) -> Option<usize> {
+        match retrigger {
+            RetriggerMode::Reset => None,
+            RetriggerMode::Analog => self.voices.iter().position(|voice| {
+                matches!(voice, Some(voice) if voice.channel == channel && voice.note == note)
+            }),
+            RetriggerMode::Legato => self
+                .voices
+                .iter()
+                .enumerate()
+                .filter_map(|(voice_idx, voice)| Some((voice_idx, voice.as_ref()?)))
+                .filter(|(_, voice)| {
+                    voice.channel == channel && !voice.releasing && !voice.pedal_held
+                })
+                .max_by_key(|(_, voice)| voice.internal_voice_id)
+                .map(|(voice_idx, _)| voice_idx),
+        }
+    }
+
+    /// Move the voice at `voice_idx` to a new note. The voice's operators and envelopes are left
+    /// alone. If the voice ID changes, then the host is told the old voice has terminated.
+    fn retrigger_voice(
+        &mut self,
+        context: &mut impl ProcessContext<Self>,
+        sample_offset: u32,
+        voice_idx: usize,
+        voice_id: Option<i32>,
+        channel: u8,
+        note: u8,
+    ) -> &mut Voice {
+        let voice = self.voices[voice_idx].as_mut().unwrap();
+        let voice_id = voice_id.unwrap_or_else(|| compute_fallback_voice_id(note, channel));
+        if voice.voice_id != voice_id {
+            context.send_event(NoteEvent::VoiceTerminated {
+                timing: sample_offset,
+                voice_id: Some(voice.voice_id),
+                channel: voice.channel,
+                note: voice.note,
+            });
+
+            // Polyphonic modulation belonged to the old voice ID
+            voice.poly_mod.clear();
+        }
+
+        voice.voice_id = voice_id;
+        voice.internal_voice_id = self.next_internal_voice_id;
+        voice.channel = channel;
+        voice.note = note;
+        voice.releasing = false;
+        voice.pedal_held = false;
+        // The new note's key went down after the sostenuto pedal, so the pedal doesn't hold it
+        voice.sostenuto = false;
+        voice.release_samples = 0;
+        self.next_internal_voice_id = self.next_internal_voice_id.wrapping_add(1);
+
+        voice
+    }
+
+    /// The number of voices that are currently playing or releasing. Stolen voices that are
+    /// fading out are not included.
+    fn active_voice_count(&self) -> usize {
+        self.voices.iter().filter(|voice| voice.is_some()).count()
+    }
+
     /// Immediately terminate one or more voice, removing it from the pool and informing the host
     /// that the voice has ended. If `voice_id` is not provided, then this will terminate all
     /// matching voices.
@@ -579,6 +1393,241 @@ impl FmSynth {
     }
 }
 
+impl Voice {
+    /// Create a voice that has not started playing yet. The note on handler fills in the rest.
+    fn new(voice_id: i32, internal_voice_id: u64, channel: u8, note: u8) -> Self {
+        Self {
+            voice_id,
+            internal_voice_id,
+            channel,
+            note,
+            velocity: 1.0,
+            velocity_gain: Smoother::new(SmoothingStyle::Linear(DECLICK_MS)),
+            releasing: false,
+            pedal_held: false,
+            sostenuto: false,
+            release_samples: 0,
+            envelope_start: None,
+            phase_delta: 0.0,
+            pitch_envelope: Envelope::default(),
+            lfos: Default::default(),
+            random: 0.0,
+            block_mod: BlockModulation::default(),
+            operators: Default::default(),
+            poly_mod: VoicePolyMod::default(),
+            aftertouch: None,
+            declick: None,
+        }
+    }
+
+    /// Whether the voice has ended, and if so, whether it needs to be faded out. Modulators don't
+    /// contribute to the output on their own, so only the carriers' envelopes need to have
+    /// released. Free looping envelopes release as soon as the note ends, and rate/level envelopes
+    /// release to their fourth level, so they're not necessarily silent yet. Voices that have
+    /// been releasing for `max_release_samples` end regardless.
+    fn end(&self, routing: &Routing, max_release_samples: u32) -> Option<VoiceEnd> {
+        let mut carrier_envelopes = self
+            .operators
+            .iter()
+            .enumerate()
+            .filter(|(op_idx, _)| routing.is_carrier(*op_idx))
+            .map(|(_, operator)| &operator.envelope);
+
+        if self.release_samples < max_release_samples
+            && !carrier_envelopes
+                .clone()
+                .all(|envelope| envelope.is_released())
+        {
+            None
+        } else if carrier_envelopes.all(|envelope| envelope.is_silent()) {
+            Some(VoiceEnd::Remove)
+        } else {
+            Some(VoiceEnd::FadeOut)
+        }
+    }
+
+    /// Recompute the level and rate scales for the operator at `op_idx` after the voice's
+    /// polyphonic modulation for its velocity sensitivity or keyboard scaling has changed. If
+    /// `reset` is set, then the level immediately jumps to the new value. The new rate scale
+    /// applies to the envelope stages that are read again while the note plays.
+    fn update_operator_scales(
+        &mut self,
+        params: &FmSynthParams,
+        op_idx: usize,
+        sample_rate: f32,
+        reset: bool,
+    ) {
+        let (level_scale, rate_scale) =
+            operator_scales(params, op_idx, self.note, self.velocity, |target| {
+                self.poly_mod.value(target, params.poly_mod_param(target))
+            });
+
+        let operator = &mut self.operators[op_idx];
+        if reset {
+            operator.level_scale.reset(level_scale);
+        } else {
+            operator.level_scale.set_target(sample_rate, level_scale);
+        }
+        operator.rate_scale = rate_scale;
+    }
+
+    /// Start the voice's envelopes if a note has started or retriggered the voice since its last
+    /// block. The envelopes' settings include the voice's polyphonic modulation.
+    fn start_envelopes(&mut self, params: &FmSynthParams, tempo: f64, sample_rate: f32) {
+        let Some(envelope_start) = self.envelope_start.take() else {
+            return;
+        };
+
+        let retrigger = envelope_start == EnvelopeStart::Retrigger;
+        for (op_idx, (operator, operator_params)) in self
+            .operators
+            .iter_mut()
+            .zip(params.operators.iter())
+            .enumerate()
+        {
+            let envelope_params = &operator_params.envelope;
+            let values = self.poly_mod.envelope_values(envelope_params, |target| {
+                PolyModTarget::OperatorEnvelope(op_idx, target)
+            });
+            start_envelope(
+                &mut operator.envelope,
+                sample_rate,
+                &envelope_params
+                    .settings(tempo, self.velocity, self.note, &values)
+                    .scale_durations(operator.rate_scale),
+                retrigger,
+            );
+        }
+        let envelope_params = &params.pitch_envelope.envelope;
+        let values = self
+            .poly_mod
+            .envelope_values(envelope_params, PolyModTarget::PitchEnvelope);
+        start_envelope(
+            &mut self.pitch_envelope,
+            sample_rate,
+            &envelope_params.settings(tempo, self.velocity, self.note, &values),
+            retrigger,
+        );
+    }
+
+    /// Move the voice's envelopes to their release stages. Envelopes that haven't started yet
+    /// because the note ends on the sample it started are started first.
+    fn start_release(&mut self, params: &FmSynthParams, tempo: f64, sample_rate: f32) {
+        self.start_envelopes(params, tempo, sample_rate);
+        self.releasing = true;
+        self.pedal_held = false;
+        for (op_idx, (operator, operator_params)) in self
+            .operators
+            .iter_mut()
+            .zip(params.operators.iter())
+            .enumerate()
+        {
+            let envelope_params = &operator_params.envelope;
+            let mut values = self.poly_mod.envelope_values(envelope_params, |target| {
+                PolyModTarget::OperatorEnvelope(op_idx, target)
+            });
+            self.block_mod
+                .apply_envelope(op_idx, envelope_params, &mut values);
+            operator.envelope.note_off(
+                sample_rate,
+                envelope_params.release_ms(self.velocity, self.note, &values) * operator.rate_scale,
+            );
+        }
+        let envelope_params = &params.pitch_envelope.envelope;
+        let values = self
+            .poly_mod
+            .envelope_values(envelope_params, PolyModTarget::PitchEnvelope);
+        self.pitch_envelope.note_off(
+            sample_rate,
+            envelope_params.release_ms(self.velocity, self.note, &values),
+        );
+    }
+}
+
+/// How a voice's envelopes start. See [`Voice::envelope_start`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EnvelopeStart {
+    /// Start from zero, or from the rate/level envelope's fourth level.
+    NoteOn,
+    /// Start from the envelopes' current values.
+    Retrigger,
+}
+
+/// Copy `settings` into `envelope` and start its attack stage. Retriggered envelopes start from
+/// their current value instead of from zero.
+fn start_envelope(
+    envelope: &mut Envelope<f32>,
+    sample_rate: f32,
+    settings: &EnvelopeSettings,
+    retrigger: bool,
+) {
+    envelope.set_settings(settings);
+    let (attack, hold, decay) = (
+        settings.attack.duration_ms,
+        settings.hold_ms,
+        settings.decay.duration_ms,
+    );
+    if retrigger {
+        envelope.retrigger(sample_rate, attack, hold, decay, settings.sustain);
+    } else {
+        envelope.note_on(sample_rate, attack, hold, decay, settings.sustain);
+    }
+}
+
+/// The level and rate scales for the operator at `op_idx` when playing `note` at `velocity`.
+/// Velocity sensitivity scales the operator's level independently from the voice's loudness. For
+/// modulators this makes the sound brighter when playing harder. `value` returns the values of the
+/// velocity sensitivity and keyboard scaling parameters.
+fn operator_scales(
+    params: &FmSynthParams,
+    op_idx: usize,
+    note: u8,
+    velocity: f32,
+    value: impl Fn(PolyModTarget) -> f32,
+) -> (f32, f32) {
+    let scaling = &params.operators[op_idx].keyboard_scaling;
+    let breakpoint = scaling.breakpoint.value();
+    let level_scale = if (note as i32) < breakpoint {
+        keyboard_level_scale(
+            note,
+            breakpoint,
+            scaling.left_curve.value(),
+            value(PolyModTarget::LeftDepth(op_idx)),
+        )
+    } else {
+        keyboard_level_scale(
+            note,
+            breakpoint,
+            scaling.right_curve.value(),
+            value(PolyModTarget::RightDepth(op_idx)),
+        )
+    };
+
+    let sensitivity = value(PolyModTarget::VelocitySensitivity(op_idx));
+    let velocity_scale = 1.0 - sensitivity * (1.0 - params.velocity_curve.value().apply(velocity));
+    let rate_scale = keyboard_rate_scale(note, value(PolyModTarget::RateScaling(op_idx)));
+
+    (level_scale * velocity_scale, rate_scale)
+}
+
+/// Convert a pitch bend in `[-1, 1]` to semitones, using the range for the direction it's bent in.
+fn pitch_bend_semitones(pitch_bend: f32, up_semitones: f32, down_semitones: f32) -> f32 {
+    if pitch_bend >= 0.0 {
+        pitch_bend * up_semitones
+    } else {
+        pitch_bend * down_semitones
+    }
+}
+
+/// How a voice ends once it has finished playing.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum VoiceEnd {
+    /// The voice is silent, so it's removed right away.
+    Remove,
+    /// The voice would still be audible, so it's faded out to avoid a click.
+    FadeOut,
+}
+
 /// Compute a voice ID in case the host doesn't provide them. Polyphonic modulation will not work in
 /// this case, but playing notes will.
 const fn compute_fallback_voice_id(note: u8, channel: u8) -> i32 {
@@ -621,3 +1670,146 @@ impl Vst3Plugin for FmSynth {
 
 nih_export_clap!(FmSynth);
 nih_export_vst3!(FmSynth);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use algorithm::Algorithm;
+    use envelope::linear;
+
+    const SAMPLE_RATE: f32 = 44_100.0;
+
+    /// Start a voice where every operator's envelope uses an AHDSR envelope with a 10 ms attack and
+    /// full sustain.
+    fn start_voice() -> Voice {
+        let settings = EnvelopeSettings {
+            attack: linear(10.0),
+            ..EnvelopeSettings::default()
+        };
+        let mut voice = Voice::new(0, 0, 0, 60);
+        for operator in &mut voice.operators {
+            start_envelope(&mut operator.envelope, SAMPLE_RATE, &settings, false);
+        }
+
+        voice
+    }
+
+    /// Release the voice's envelopes over `release_ms`, without reading the release from the
+    /// parameters like [`Voice::start_release()`] does.
+    fn release_voice(voice: &mut Voice, release_ms: f32) {
+        voice.releasing = true;
+        for operator in &mut voice.operators {
+            operator.envelope.note_off(SAMPLE_RATE, release_ms);
+        }
+    }
+
+    /// Render the voice's envelopes in blocks, and keep track of the release time like
+    /// `process()` does.
+    fn render_envelopes(voice: &mut Voice, num_samples: usize) {
+        let mut values = [0.0; MAX_BLOCK_SIZE];
+        for block_start in (0..num_samples).step_by(MAX_BLOCK_SIZE) {
+            let block_len = MAX_BLOCK_SIZE.min(num_samples - block_start);
+            for operator in &mut voice.operators {
+                operator.envelope.next_block(&mut values, block_len);
+            }
+            if voice.releasing {
+                voice.release_samples += block_len as u32;
+            }
+        }
+    }
+
+    #[test]
+    fn stealing_more_voices_than_slots_replaces_the_quietest_stolen_voice() {
+        let mut synth = FmSynth::default();
+        for note in 0..NUM_VOICES as u8 {
+            synth.fade_out_voice(Voice::new(0, note as u64, 0, note), SAMPLE_RATE);
+        }
+        assert!(synth.stolen_voices.iter().all(|voice| voice.is_some()));
+
+        // The fifth stolen voice has faded out the furthest
+        let mut declick = [0.0; MAX_BLOCK_SIZE];
+        synth.stolen_voices[4]
+            .as_ref()
+            .and_then(|voice| voice.declick.as_ref())
+            .unwrap()
+            .next_block(&mut declick, MAX_BLOCK_SIZE);
+
+        synth.fade_out_voice(Voice::new(0, 100, 0, 100), SAMPLE_RATE);
+        let notes: Vec<u8> = synth
+            .stolen_voices
+            .iter()
+            .map(|voice| voice.as_ref().unwrap().note)
+            .collect();
+        let mut expected_notes: Vec<u8> = (0..NUM_VOICES as u8).collect();
+        expected_notes[4] = 100;
+        assert_eq!(notes, expected_notes);
+    }
+
+    #[test]
+    fn legato_skips_voices_only_held_by_a_pedal() {
+        let mut synth = FmSynth::default();
+        let mut pedal_held_voice = Voice::new(0, 1, 0, 60);
+        pedal_held_voice.pedal_held = true;
+        synth.voices[0] = Some(pedal_held_voice);
+        assert_eq!(
+            synth.get_retrigger_voice_idx(RetriggerMode::Legato, 0, 64),
+            None
+        );
+
+        synth.voices[1] = Some(Voice::new(1, 0, 0, 62));
+        assert_eq!(
+            synth.get_retrigger_voice_idx(RetriggerMode::Legato, 0, 64),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn pitch_bend_uses_the_range_for_its_direction() {
+        assert_eq!(pitch_bend_semitones(0.0, 2.0, 12.0), 0.0);
+        assert_eq!(pitch_bend_semitones(1.0, 2.0, 12.0), 2.0);
+        assert_eq!(pitch_bend_semitones(0.5, 2.0, 12.0), 1.0);
+        assert_eq!(pitch_bend_semitones(-1.0, 2.0, 12.0), -12.0);
+        assert_eq!(pitch_bend_semitones(-0.25, 2.0, 12.0), -3.0);
+        assert_eq!(pitch_bend_semitones(-1.0, 2.0, 0.0), 0.0);
+    }
+
+    #[test]
+    fn silent_voices_are_removed() {
+        let routing = Algorithm::A1.routing();
+        let max_release_samples = (MAX_RELEASE_SECONDS * SAMPLE_RATE) as u32;
+        let mut voice = start_voice();
+        render_envelopes(&mut voice, SAMPLE_RATE as usize);
+        assert_eq!(voice.end(routing, max_release_samples), None);
+
+        release_voice(&mut voice, 100.0);
+        render_envelopes(&mut voice, (0.05 * SAMPLE_RATE) as usize);
+        assert_eq!(voice.end(routing, max_release_samples), None);
+        render_envelopes(&mut voice, (0.05 * SAMPLE_RATE) as usize + 1);
+        assert_eq!(
+            voice.end(routing, max_release_samples),
+            Some(VoiceEnd::Remove)
+        );
+    }
+
+    #[test]
+    fn release_time_limit_fades_out_voices() {
+        let routing = Algorithm::A1.routing();
+        let mut voice = start_voice();
+        render_envelopes(&mut voice, SAMPLE_RATE as usize);
+
+        // The envelopes would take a minute and a half to release, so the limit is hit first
+        let max_release_samples = (MAX_RELEASE_SECONDS * SAMPLE_RATE) as u32;
+        release_voice(&mut voice, MAX_RELEASE_SECONDS * 1500.0);
+        render_envelopes(&mut voice, max_release_samples as usize - MAX_BLOCK_SIZE);
+        assert_eq!(voice.end(routing, max_release_samples), None);
+        render_envelopes(&mut voice, MAX_BLOCK_SIZE);
+        assert!(voice
+            .operators
+            .iter()
+            .all(|operator| !operator.envelope.is_released()));
+        assert_eq!(
+            voice.end(routing, max_release_samples),
+            Some(VoiceEnd::FadeOut)
+        );
+    }
+}
@@ -0,0 +1,599 @@
+use crate::{
+    lfo::{LfoSettings, NUM_LFOS},
+    operator::NUM_OPERATORS,
+    params::{EnvelopeParams, EnvelopeValues, LfoParams},
+    MAX_BLOCK_SIZE,
+};
+use nih_plug::prelude::*;
+
+/// The number of slots in the mod matrix.
+pub const NUM_MOD_SLOTS: usize = 8;
+
+/// How far the voice's pitch moves at full depth, in semitones.
+pub const MOD_PITCH_RANGE: f32 = 12.0;
+
+// The sources and destinations below name every LFO and operator, so they need to be updated
+// together with these constants
+const _: () = assert!(NUM_LFOS == 2 && NUM_OPERATORS == 6);
+
+/// Where a mod matrix slot gets its modulation from. LFOs and random values are bipolar, all other
+/// sources are in `[0, 1]`.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ModSource {
+    #[name = "None"]
+    None,
+    #[name = "LFO 1"]
+    Lfo1,
+    #[name = "LFO 2"]
+    Lfo2,
+    #[name = "Pitch Envelope"]
+    PitchEnvelope,
+    #[name = "Op 1 Envelope"]
+    Envelope1,
+    #[name = "Op 2 Envelope"]
+    Envelope2,
+    #[name = "Op 3 Envelope"]
+    Envelope3,
+    #[name = "Op 4 Envelope"]
+    Envelope4,
+    #[name = "Op 5 Envelope"]
+    Envelope5,
+    #[name = "Op 6 Envelope"]
+    Envelope6,
+    #[name = "Velocity"]
+    Velocity,
+    /// The note's key, where 0 is the lowest MIDI note and 1 is the highest.
+    #[name = "Key"]
+    Key,
+    #[name = "Mod Wheel"]
+    ModWheel,
+    #[name = "Expression"]
+    Expression,
+    /// Polyphonic aftertouch for the voice, or channel pressure if the voice has not received any.
+    #[name = "Aftertouch"]
+    Aftertouch,
+    /// A random value that's picked once for every new voice.
+    #[name = "Random"]
+    Random,
+    /// The macro parameter, which the host can modulate per voice.
+    #[name = "Macro"]
+    Macro,
+}
+
+/// What a mod matrix slot modulates. The modulation is added to the destination parameter's
+/// normalized value. Destinations starting with "Op" use the slot's operator.
+///
+/// The envelopes and LFOs are also modulation sources, so they are rendered before the matrix is
+/// evaluated. The envelope times, the sustain level and the LFO rates are read once per block, and
+/// they use the matrix's values from the end of the previous block. Attack and hold times are only
+/// read when a note starts, so they cannot be modulated.
+///
+/// Only the parameters listed here can be modulated. The other continuous parameters, like the LFO
+/// delay and fade in, the envelope curves' powers, velocity sensitivity, keyboard scaling and the
+/// slots' own depths, can only be modulated polyphonically by the host.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ModDestination {
+    #[name = "None"]
+    None,
+    #[name = "Gain"]
+    Gain,
+    /// Slots that modulate the macro are evaluated before the other slots, so those use the
+    /// modulated macro. The macro cannot modulate itself.
+    #[name = "Macro"]
+    Macro,
+    /// The pitch of every operator that's not in fixed frequency mode. This is not a parameter, so
+    /// full depth moves the pitch by [`MOD_PITCH_RANGE`] semitones.
+    #[name = "Pitch"]
+    Pitch,
+    #[name = "Pitch Envelope Depth"]
+    PitchEnvelopeDepth,
+    #[name = "LFO 1 Pitch Depth"]
+    Lfo1PitchDepth,
+    #[name = "LFO 2 Pitch Depth"]
+    Lfo2PitchDepth,
+    #[name = "LFO 1 Gain Depth"]
+    Lfo1GainDepth,
+    #[name = "LFO 2 Gain Depth"]
+    Lfo2GainDepth,
+    #[name = "LFO 1 Op Level Depth"]
+    Lfo1LevelDepth,
+    #[name = "LFO 2 Op Level Depth"]
+    Lfo2LevelDepth,
+    /// Global LFOs are shared by all voices, so this only affects per voice LFOs. Tempo synced
+    /// LFOs are not affected.
+    #[name = "LFO 1 Rate"]
+    Lfo1Rate,
+    #[name = "LFO 2 Rate"]
+    Lfo2Rate,
+    #[name = "Op Level"]
+    Level,
+    #[name = "Op Fine"]
+    Fine,
+    #[name = "Op Detune"]
+    Detune,
+    #[name = "Op Fixed Frequency"]
+    FixedFrequency,
+    #[name = "Op Feedback"]
+    Feedback,
+    /// How much the first operator modulates the slot's operator. If that's the slot's operator
+    /// itself, then this is added to the feedback.
+    #[name = "Op Mod From Op 1"]
+    ModulationFrom1,
+    #[name = "Op Mod From Op 2"]
+    ModulationFrom2,
+    #[name = "Op Mod From Op 3"]
+    ModulationFrom3,
+    #[name = "Op Mod From Op 4"]
+    ModulationFrom4,
+    #[name = "Op Mod From Op 5"]
+    ModulationFrom5,
+    #[name = "Op Mod From Op 6"]
+    ModulationFrom6,
+    /// The decay time, or the second and third rates for rate/level envelopes.
+    #[name = "Op Decay"]
+    Decay,
+    /// The sustain level, or the third level for rate/level envelopes.
+    #[name = "Op Sustain"]
+    Sustain,
+    /// The release time, or the fourth rate for rate/level envelopes.
+    #[name = "Op Release"]
+    Release,
+}
+
+/// The settings for a single mod matrix slot. The depth is smoothed, so it's rendered separately.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ModSlot {
+    pub source: ModSource,
+    pub destination: ModDestination,
+    /// The zero based index of the operator for per operator destinations.
+    pub operator: usize,
+}
+
+/// The values of all modulation sources for a single voice during a single block.
+pub struct ModSources<'a> {
+    pub lfos: [&'a [f32; MAX_BLOCK_SIZE]; NUM_LFOS],
+    pub pitch_envelope: &'a [f32; MAX_BLOCK_SIZE],
+    pub envelopes: &'a [[f32; MAX_BLOCK_SIZE]; NUM_OPERATORS],
+    pub velocity: f32,
+    pub note: u8,
+    pub mod_wheel: &'a [f32; MAX_BLOCK_SIZE],
+    pub expression: &'a [f32; MAX_BLOCK_SIZE],
+    pub aftertouch: &'a [f32; MAX_BLOCK_SIZE],
+    /// The voice's random value in `[-1, 1]`.
+    pub random: f32,
+    pub macro_amount: &'a [f32; MAX_BLOCK_SIZE],
+}
+
+/// The summed modulation for a single destination during a single block. Destinations that no
+/// slot modulates are inactive, so the voices can skip them entirely.
+#[derive(Debug, Clone, Copy)]
+pub struct ModBlock {
+    active: bool,
+    values: [f32; MAX_BLOCK_SIZE],
+}
+
+/// The summed modulation for every destination for a single voice during a single block. This is
+/// kept between blocks and reused for every voice, so evaluating the matrix does not allocate.
+#[derive(Debug, Clone)]
+pub struct ModMatrixBlock {
+    pub gain: ModBlock,
+    pub macro_amount: ModBlock,
+    pub pitch: ModBlock,
+    pub pitch_envelope_depth: ModBlock,
+    pub lfo_pitch_depths: [ModBlock; NUM_LFOS],
+    pub lfo_gain_depths: [ModBlock; NUM_LFOS],
+    /// Indexed by the LFO and then by the operator.
+    pub lfo_level_depths: [[ModBlock; NUM_OPERATORS]; NUM_LFOS],
+    lfo_rates: [ModBlock; NUM_LFOS],
+    pub levels: [ModBlock; NUM_OPERATORS],
+    pub fines: [ModBlock; NUM_OPERATORS],
+    pub detunes: [ModBlock; NUM_OPERATORS],
+    pub fixed_frequencies: [ModBlock; NUM_OPERATORS],
+    pub feedbacks: [ModBlock; NUM_OPERATORS],
+    /// Indexed by the modulated operator and then by the modulator, like the operators'
+    /// modulation parameters.
+    pub modulations: [[ModBlock; NUM_OPERATORS]; NUM_OPERATORS],
+    decays: [ModBlock; NUM_OPERATORS],
+    sustains: [ModBlock; NUM_OPERATORS],
+    releases: [ModBlock; NUM_OPERATORS],
+}
+
+/// The mod matrix's normalized offsets for the destinations that are only read once per block. A
+/// voice keeps these from the end of one block and applies them during the next block.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct BlockModulation {
+    decays: [f32; NUM_OPERATORS],
+    sustains: [f32; NUM_OPERATORS],
+    releases: [f32; NUM_OPERATORS],
+    lfo_rates: [f32; NUM_LFOS],
+}
+
+impl ModSources<'_> {
+    fn value(&self, source: ModSource, value_idx: usize) -> f32 {
+        match source {
+            ModSource::None => 0.0,
+            ModSource::Lfo1 => self.lfos[0][value_idx],
+            ModSource::Lfo2 => self.lfos[1][value_idx],
+            ModSource::PitchEnvelope => self.pitch_envelope[value_idx],
+            ModSource::Envelope1 => self.envelopes[0][value_idx],
+            ModSource::Envelope2 => self.envelopes[1][value_idx],
+            ModSource::Envelope3 => self.envelopes[2][value_idx],
+            ModSource::Envelope4 => self.envelopes[3][value_idx],
+            ModSource::Envelope5 => self.envelopes[4][value_idx],
+            ModSource::Envelope6 => self.envelopes[5][value_idx],
+            ModSource::Velocity => self.velocity,
+            ModSource::Key => self.note as f32 / 127.0,
+            ModSource::ModWheel => self.mod_wheel[value_idx],
+            ModSource::Expression => self.expression[value_idx],
+            ModSource::Aftertouch => self.aftertouch[value_idx],
+            ModSource::Random => self.random,
+            ModSource::Macro => self.macro_amount[value_idx],
+        }
+    }
+}
+
+impl ModBlock {
+    const INACTIVE: Self = Self {
+        active: false,
+        values: [0.0; MAX_BLOCK_SIZE],
+    };
+
+    /// The summed modulation at `value_idx`.
+    pub fn get(&self, value_idx: usize) -> f32 {
+        if self.active {
+            self.values[value_idx]
+        } else {
+            0.0
+        }
+    }
+
+    /// Apply the modulation at `value_idx` to `plain_value`, the smoothed value of `param`. The
+    /// result stays within the parameter's range.
+    pub fn apply(&self, param: &FloatParam, value_idx: usize, plain_value: f32) -> f32 {
+        if self.active {
+            modulate(param, plain_value, self.values[value_idx])
+        } else {
+            plain_value
+        }
+    }
+}
+
+impl BlockModulation {
+    /// Apply the modulation to the envelope values for the operator at `op_idx`.
+    pub fn apply_envelope(
+        &self,
+        op_idx: usize,
+        params: &EnvelopeParams,
+        values: &mut EnvelopeValues,
+    ) {
+        let (decay, sustain, release) = (
+            self.decays[op_idx],
+            self.sustains[op_idx],
+            self.releases[op_idx],
+        );
+
+        values.decay_ms = modulate(&params.decay_ms, values.decay_ms, decay);
+        values.sustain_percentage = modulate(
+            &params.sustain_percentage,
+            values.sustain_percentage,
+            sustain,
+        );
+        values.release_ms = modulate(&params.release_ms, values.release_ms, release);
+        values.rate_2_ms = modulate(&params.rate_2_ms, values.rate_2_ms, decay);
+        values.rate_3_ms = modulate(&params.rate_3_ms, values.rate_3_ms, decay);
+        values.rate_4_ms = modulate(&params.rate_4_ms, values.rate_4_ms, release);
+        values.level_3_percentage = modulate(
+            &params.level_3_percentage,
+            values.level_3_percentage,
+            sustain,
+        );
+    }
+
+    /// Apply the modulation to the settings of the per voice LFO at `lfo_idx`.
+    pub fn apply_lfo(&self, lfo_idx: usize, params: &LfoParams, settings: &mut LfoSettings) {
+        if !params.sync.value() {
+            settings.rate_hz = modulate(&params.rate_hz, settings.rate_hz, self.lfo_rates[lfo_idx]);
+        }
+    }
+}
+
+/// Add the normalized modulation `offset` to `plain_value`, a value of `param`. The result stays
+/// within the parameter's range.
+fn modulate(param: &FloatParam, plain_value: f32, offset: f32) -> f32 {
+    if offset == 0.0 {
+        plain_value
+    } else {
+        param.preview_plain(param.preview_normalized(plain_value) + offset)
+    }
+}
+
+impl Default for ModMatrixBlock {
+    fn default() -> Self {
+        Self {
+            gain: ModBlock::INACTIVE,
+            macro_amount: ModBlock::INACTIVE,
+            pitch: ModBlock::INACTIVE,
+            pitch_envelope_depth: ModBlock::INACTIVE,
+            lfo_pitch_depths: [ModBlock::INACTIVE; NUM_LFOS],
+            lfo_gain_depths: [ModBlock::INACTIVE; NUM_LFOS],
+            lfo_level_depths: [[ModBlock::INACTIVE; NUM_OPERATORS]; NUM_LFOS],
+            lfo_rates: [ModBlock::INACTIVE; NUM_LFOS],
+            levels: [ModBlock::INACTIVE; NUM_OPERATORS],
+            fines: [ModBlock::INACTIVE; NUM_OPERATORS],
+            detunes: [ModBlock::INACTIVE; NUM_OPERATORS],
+            fixed_frequencies: [ModBlock::INACTIVE; NUM_OPERATORS],
+            feedbacks: [ModBlock::INACTIVE; NUM_OPERATORS],
+            modulations: [[ModBlock::INACTIVE; NUM_OPERATORS]; NUM_OPERATORS],
+            decays: [ModBlock::INACTIVE; NUM_OPERATORS],
+            sustains: [ModBlock::INACTIVE; NUM_OPERATORS],
+            releases: [ModBlock::INACTIVE; NUM_OPERATORS],
+        }
+    }
+}
+
+impl ModMatrixBlock {
+    /// Mark every destination as inactive before evaluating the matrix for another voice.
+    fn clear(&mut self) {
+        for block in [
+            &mut self.gain,
+            &mut self.macro_amount,
+            &mut self.pitch,
+            &mut self.pitch_envelope_depth,
+        ]
+        .into_iter()
+        .chain(self.lfo_pitch_depths.iter_mut())
+        .chain(self.lfo_gain_depths.iter_mut())
+        .chain(self.lfo_level_depths.iter_mut().flatten())
+        .chain(self.lfo_rates.iter_mut())
+        .chain(self.levels.iter_mut())
+        .chain(self.fines.iter_mut())
+        .chain(self.detunes.iter_mut())
+        .chain(self.fixed_frequencies.iter_mut())
+        .chain(self.feedbacks.iter_mut())
+        .chain(self.modulations.iter_mut().flatten())
+        .chain(self.decays.iter_mut())
+        .chain(self.sustains.iter_mut())
+        .chain(self.releases.iter_mut())
+        {
+            block.active = false;
+        }
+    }
+
+    /// Whether the slots modulate any of the parameters that determine the operator's frequency.
+    pub fn modulates_frequency(&self, op_idx: usize) -> bool {
+        self.fines[op_idx].active
+            || self.detunes[op_idx].active
+            || self.fixed_frequencies[op_idx].active
+    }
+
+    /// The modulation at the end of the block for the destinations that are only read once per
+    /// block.
+    pub fn block_modulation(&self, block_len: usize) -> BlockModulation {
+        let value_idx = block_len.saturating_sub(1);
+
+        BlockModulation {
+            decays: std::array::from_fn(|op_idx| self.decays[op_idx].get(value_idx)),
+            sustains: std::array::from_fn(|op_idx| self.sustains[op_idx].get(value_idx)),
+            releases: std::array::from_fn(|op_idx| self.releases[op_idx].get(value_idx)),
+            lfo_rates: std::array::from_fn(|lfo_idx| self.lfo_rates[lfo_idx].get(value_idx)),
+        }
+    }
+
+    /// Evaluate all `slots` for a voice. `depths` returns the smoothed depth for the slot at an
+    /// index. Slots that modulate the macro are evaluated first, so the other slots use the
+    /// modulated macro. `sources` contains the unmodulated macro, and the modulated macro is
+    /// written to `macro_amount`. `macro_param` is the macro parameter.
+    pub fn evaluate<'a>(
+        &mut self,
+        slots: &[ModSlot; NUM_MOD_SLOTS],
+        depths: impl Fn(usize) -> &'a [f32; MAX_BLOCK_SIZE],
+        sources: ModSources,
+        macro_param: &FloatParam,
+        macro_amount: &mut [f32; MAX_BLOCK_SIZE],
+        block_len: usize,
+    ) {
+        self.clear();
+
+        let macro_slots = |is_macro: bool| {
+            slots
+                .iter()
+                .enumerate()
+                .filter(move |(_, slot)| (slot.destination == ModDestination::Macro) == is_macro)
+        };
+        for (slot_idx, slot) in macro_slots(true) {
+            self.add(slot, depths(slot_idx), &sources, block_len);
+        }
+        for (value_idx, value) in macro_amount[..block_len].iter_mut().enumerate() {
+            *value =
+                self.macro_amount
+                    .apply(macro_param, value_idx, sources.macro_amount[value_idx]);
+        }
+
+        let sources = ModSources {
+            macro_amount: &*macro_amount,
+            ..sources
+        };
+        for (slot_idx, slot) in macro_slots(false) {
+            self.add(slot, depths(slot_idx), &sources, block_len);
+        }
+    }
+
+    /// Add a slot's modulation to its destination. `depths` contains the slot's smoothed depth.
+    fn add(
+        &mut self,
+        slot: &ModSlot,
+        depths: &[f32; MAX_BLOCK_SIZE],
+        sources: &ModSources,
+        block_len: usize,
+    ) {
+        if slot.source == ModSource::None {
+            return;
+        }
+        let block = match slot.destination {
+            ModDestination::None => return,
+            ModDestination::Gain => &mut self.gain,
+            // The macro cannot modulate itself
+            ModDestination::Macro if slot.source == ModSource::Macro => return,
+            ModDestination::Macro => &mut self.macro_amount,
+            ModDestination::Pitch => &mut self.pitch,
+            ModDestination::PitchEnvelopeDepth => &mut self.pitch_envelope_depth,
+            ModDestination::Lfo1PitchDepth => &mut self.lfo_pitch_depths[0],
+            ModDestination::Lfo2PitchDepth => &mut self.lfo_pitch_depths[1],
+            ModDestination::Lfo1GainDepth => &mut self.lfo_gain_depths[0],
+            ModDestination::Lfo2GainDepth => &mut self.lfo_gain_depths[1],
+            ModDestination::Lfo1LevelDepth => &mut self.lfo_level_depths[0][slot.operator],
+            ModDestination::Lfo2LevelDepth => &mut self.lfo_level_depths[1][slot.operator],
+            ModDestination::Lfo1Rate => &mut self.lfo_rates[0],
+            ModDestination::Lfo2Rate => &mut self.lfo_rates[1],
+            ModDestination::Level => &mut self.levels[slot.operator],
+            ModDestination::Fine => &mut self.fines[slot.operator],
+            ModDestination::Detune => &mut self.detunes[slot.operator],
+            ModDestination::FixedFrequency => &mut self.fixed_frequencies[slot.operator],
+            ModDestination::Feedback => &mut self.feedbacks[slot.operator],
+            ModDestination::ModulationFrom1 => &mut self.modulations[slot.operator][0],
+            ModDestination::ModulationFrom2 => &mut self.modulations[slot.operator][1],
+            ModDestination::ModulationFrom3 => &mut self.modulations[slot.operator][2],
+            ModDestination::ModulationFrom4 => &mut self.modulations[slot.operator][3],
+            ModDestination::ModulationFrom5 => &mut self.modulations[slot.operator][4],
+            ModDestination::ModulationFrom6 => &mut self.modulations[slot.operator][5],
+            ModDestination::Decay => &mut self.decays[slot.operator],
+            ModDestination::Sustain => &mut self.sustains[slot.operator],
+            ModDestination::Release => &mut self.releases[slot.operator],
+        };
+
+        if !block.active {
+            block.values[..block_len].fill(0.0);
+            block.active = true;
+        }
+        for (value_idx, (value, depth)) in
+            block.values[..block_len].iter_mut().zip(depths).enumerate()
+        {
+            *value += depth * sources.value(slot.source, value_idx);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK_LEN: usize = 16;
+
+    /// A slot for the first operator.
+    fn slot(source: ModSource, destination: ModDestination) -> ModSlot {
+        ModSlot {
+            source,
+            destination,
+            operator: 0,
+        }
+    }
+
+    fn macro_param() -> FloatParam {
+        FloatParam::new("Macro", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+    }
+
+    /// Evaluate `slots` with every slot at `depth`, an LFO 1 ramping from -1 to 1 and a mod wheel
+    /// at 0.5. The macro is at 0.25. Returns the modulated macro.
+    fn evaluate(
+        matrix: &mut ModMatrixBlock,
+        slots: &[ModSlot; NUM_MOD_SLOTS],
+        depth: f32,
+    ) -> [f32; MAX_BLOCK_SIZE] {
+        let depths = [depth; MAX_BLOCK_SIZE];
+        let silence = [0.0; MAX_BLOCK_SIZE];
+        let lfo: [f32; MAX_BLOCK_SIZE] =
+            std::array::from_fn(|value_idx| value_idx as f32 / (BLOCK_LEN - 1) as f32 * 2.0 - 1.0);
+        let mod_wheel = [0.5; MAX_BLOCK_SIZE];
+        let envelopes = [[1.0; MAX_BLOCK_SIZE]; NUM_OPERATORS];
+        let unmodulated_macro = [0.25; MAX_BLOCK_SIZE];
+        let sources = ModSources {
+            lfos: [&lfo, &silence],
+            pitch_envelope: &silence,
+            envelopes: &envelopes,
+            velocity: 0.8,
+            note: 127,
+            mod_wheel: &mod_wheel,
+            expression: &silence,
+            aftertouch: &silence,
+            random: -0.5,
+            macro_amount: &unmodulated_macro,
+        };
+
+        let mut macro_amount = [0.0; MAX_BLOCK_SIZE];
+        matrix.evaluate(
+            slots,
+            |_| &depths,
+            sources,
+            &macro_param(),
+            &mut macro_amount,
+            BLOCK_LEN,
+        );
+
+        macro_amount
+    }
+
+    #[test]
+    fn slots_scale_their_sources_by_their_depth() {
+        let mut slots = [slot(ModSource::None, ModDestination::None); NUM_MOD_SLOTS];
+        slots[0] = slot(ModSource::Velocity, ModDestination::Level);
+        slots[1] = ModSlot {
+            operator: 3,
+            ..slot(ModSource::Lfo1, ModDestination::Fine)
+        };
+        slots[2] = slot(ModSource::Random, ModDestination::Level);
+        slots[3] = slot(ModSource::Key, ModDestination::Gain);
+
+        let mut matrix = ModMatrixBlock::default();
+        evaluate(&mut matrix, &slots, 0.5);
+        for value_idx in 0..BLOCK_LEN {
+            let lfo = value_idx as f32 / (BLOCK_LEN - 1) as f32 * 2.0 - 1.0;
+            // Sources that modulate the same destination are summed
+            assert_eq!(matrix.levels[0].get(value_idx), 0.5 * 0.8 + 0.5 * -0.5);
+            assert_eq!(matrix.fines[3].get(value_idx), 0.5 * lfo);
+            assert_eq!(matrix.gain.get(value_idx), 0.5);
+        }
+        assert!(!matrix.fines[0].active);
+        assert!(!matrix.levels[3].active);
+        assert!(matrix.modulates_frequency(3));
+        assert!(!matrix.modulates_frequency(0));
+
+        // Slots without a source don't activate their destination
+        slots = [slot(ModSource::None, ModDestination::Level); NUM_MOD_SLOTS];
+        evaluate(&mut matrix, &slots, 1.0);
+        assert!(!matrix.levels[0].active);
+        assert_eq!(matrix.levels[0].get(0), 0.0);
+    }
+
+    #[test]
+    fn macro_slots_are_evaluated_before_the_other_slots() {
+        let mut slots = [slot(ModSource::None, ModDestination::None); NUM_MOD_SLOTS];
+        // The slot using the macro comes before the slot modulating it
+        slots[0] = slot(ModSource::Macro, ModDestination::Gain);
+        slots[1] = slot(ModSource::ModWheel, ModDestination::Macro);
+        // The macro cannot modulate itself
+        slots[2] = slot(ModSource::Macro, ModDestination::Macro);
+
+        let mut matrix = ModMatrixBlock::default();
+        let macro_amount = evaluate(&mut matrix, &slots, 1.0);
+        for (value_idx, value) in macro_amount[..BLOCK_LEN].iter().enumerate() {
+            assert_eq!(*value, 0.75);
+            assert_eq!(matrix.gain.get(value_idx), 0.75);
+        }
+
+        // The modulated macro stays within the parameter's range
+        let macro_amount = evaluate(&mut matrix, &[slots[1]; NUM_MOD_SLOTS], 1.0);
+        assert_eq!(macro_amount[0], 1.0);
+    }
+
+    #[test]
+    fn block_modulation_uses_the_end_of_the_block() {
+        let mut slots = [slot(ModSource::None, ModDestination::None); NUM_MOD_SLOTS];
+        slots[0] = slot(ModSource::Lfo1, ModDestination::Decay);
+
+        let mut matrix = ModMatrixBlock::default();
+        evaluate(&mut matrix, &slots, 0.5);
+        let block_mod = matrix.block_modulation(BLOCK_LEN);
+        assert_eq!(block_mod.decays[0], 0.5);
+        assert_eq!(block_mod.releases[0], 0.0);
+    }
+}
@@ -0,0 +1,321 @@
+use crate::envelope::Envelope;
+use nih_plug::prelude::{Enum, Smoother, SmoothingStyle};
+use std::f32::consts;
+
+/// The number of operators in every voice.
+pub const NUM_OPERATORS: usize = 6;
+
+/// The shape of an operator's oscillator. Apart from the sine wave, these are the waveforms found
+/// on Yamaha's OPL and OPM chips, plus a triangle and a saw wave.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Waveform {
+    #[name = "Sine"]
+    Sine,
+    /// Only the positive half of the sine wave, silent for the second half of the cycle.
+    #[name = "Half Sine"]
+    HalfSine,
+    /// Both halves of the sine wave made positive.
+    #[name = "Absolute Sine"]
+    AbsoluteSine,
+    /// Only the first quarter of the sine wave, silent for the rest of the cycle.
+    #[name = "Quarter Sine"]
+    QuarterSine,
+    /// The rising quarters of the absolute sine wave, silent in between.
+    #[name = "Pulse Sine"]
+    PulseSine,
+    #[name = "Triangle"]
+    Triangle,
+    #[name = "Saw"]
+    Saw,
+}
+
+impl Waveform {
+    /// Compute the waveform's value at `phase`, in cycles. The phase does not need to be wrapped
+    /// to `[0, 1)` first.
+    pub fn sample(self, phase: f32) -> f32 {
+        let phase = phase.rem_euclid(1.0);
+        let sine = || (phase * consts::TAU).sin();
+
+        match self {
+            Waveform::Sine => sine(),
+            Waveform::HalfSine if phase < 0.5 => sine(),
+            Waveform::AbsoluteSine => sine().abs(),
+            Waveform::QuarterSine if phase < 0.25 => sine(),
+            Waveform::PulseSine if phase % 0.5 < 0.25 => sine().abs(),
+            Waveform::HalfSine | Waveform::QuarterSine | Waveform::PulseSine => 0.0,
+            Waveform::Triangle => 1.0 - 4.0 * ((phase + 0.25).fract() - 0.5).abs(),
+            Waveform::Saw => 2.0 * (phase + 0.5).fract() - 1.0,
+        }
+    }
+}
+
+/// Convert an operator's coarse frequency parameter to a frequency ratio. Like on the DX7, a
+/// coarse value of 0 results in a ratio of 0.5.
+pub fn coarse_ratio(coarse: i32) -> f32 {
+    if coarse == 0 {
+        0.5
+    } else {
+        coarse as f32
+    }
+}
+
+/// Compute an operator's frequency from its coarse ratio, fine ratio, detune and fixed frequency
+/// parameters. Returns the ratio to multiply the voice's phase increment with, and a phase
+/// increment that does not depend on the note. Only one of the two is non-zero.
+pub fn operator_frequency(
+    coarse_ratio: f32,
+    fine: f32,
+    detune_cents: f32,
+    fixed: bool,
+    fixed_frequency: f32,
+    sample_rate: f32,
+) -> (f32, f32) {
+    let detune = 2.0f32.powf(detune_cents / 1200.0);
+    if fixed {
+        (0.0, fixed_frequency * detune / sample_rate)
+    } else {
+        (coarse_ratio * (1.0 + fine) * detune, 0.0)
+    }
+}
+
+/// The distance from the breakpoint in octaves at which keyboard level scaling reaches its full
+/// depth.
+const KEYBOARD_SCALING_RANGE_OCTAVES: f32 = 4.0;
+
+/// Rate scaling only ever shortens envelopes. Notes at or below this note, the lowest key on an 88
+/// key keyboard, are not affected.
+const RATE_SCALING_REFERENCE_NOTE: u8 = 21;
+
+/// The curves used for keyboard level scaling on either side of the breakpoint. These match the
+/// curves on the DX7.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ScalingCurve {
+    #[name = "-Linear"]
+    NegativeLinear,
+    #[name = "-Exponential"]
+    NegativeExponential,
+    #[name = "+Exponential"]
+    PositiveExponential,
+    #[name = "+Linear"]
+    PositiveLinear,
+}
+
+/// Compute the gain multiplier keyboard level scaling applies to an operator's output level.
+/// `curve` and `depth` are the settings for the side of `breakpoint` `note` falls on. The result
+/// lies in `[0, 2]`, with negative curves attenuating and positive curves boosting the level.
+pub fn keyboard_level_scale(note: u8, breakpoint: i32, curve: ScalingCurve, depth: f32) -> f32 {
+    let octaves = (note as i32 - breakpoint).abs() as f32 / 12.0;
+    let amount = match curve {
+        ScalingCurve::NegativeLinear | ScalingCurve::PositiveLinear => {
+            octaves / KEYBOARD_SCALING_RANGE_OCTAVES
+        }
+        ScalingCurve::NegativeExponential | ScalingCurve::PositiveExponential => {
+            (2.0f32.powf(octaves) - 1.0) / (2.0f32.powf(KEYBOARD_SCALING_RANGE_OCTAVES) - 1.0)
+        }
+    };
+    let amount = (amount * depth).min(1.0);
+
+    match curve {
+        ScalingCurve::NegativeLinear | ScalingCurve::NegativeExponential => 1.0 - amount,
+        ScalingCurve::PositiveExponential | ScalingCurve::PositiveLinear => 1.0 + amount,
+    }
+}
+
+/// Compute the multiplier rate scaling applies to an operator's envelope times. At full rate
+/// scaling the envelope times halve every two octaves above [`RATE_SCALING_REFERENCE_NOTE`].
+pub fn keyboard_rate_scale(note: u8, rate_scaling: f32) -> f32 {
+    let octaves = note.saturating_sub(RATE_SCALING_REFERENCE_NOTE) as f32 / 12.0;
+
+    2.0f32.powf(-rate_scaling * octaves / 2.0)
+}
+
+/// How long changes to an operator's level scale take when a sounding voice is retriggered, in
+/// milliseconds.
+const LEVEL_SCALE_SMOOTHING_MS: f32 = 5.0;
+
+/// A single phase modulated oscillator within a voice. Operators feed their output into the phase
+/// of other operators, and the carriers are summed into the voice's output.
+#[derive(Debug, Clone)]
+pub struct Operator {
+    /// The operator's current phase, in `[0, 1)`.
+    pub phase: f32,
+    /// The sample produced by the last call to [`next_sample()`][Self::next_sample()]. This is
+    /// what gets fed into the phase of the operators this operator modulates.
+    pub output: f32,
+    /// The sample produced before [`output`][Self::output]. Feedback uses the average of the last
+    /// two samples.
+    previous_output: f32,
+    /// Fades between 0 and 1 and scales the operator's output level.
+    pub envelope: Envelope<f32>,
+    /// The gain multiplier from keyboard level scaling and velocity sensitivity. This is computed
+    /// when the note starts, and it's smoothed when a retriggered voice changes it.
+    pub level_scale: Smoother<f32>,
+    /// The multiplier for the envelope times from rate scaling. This is computed when the note
+    /// starts.
+    pub rate_scale: f32,
+}
+
+impl Default for Operator {
+    fn default() -> Self {
+        Self {
+            phase: 0.0,
+            output: 0.0,
+            previous_output: 0.0,
+            envelope: Envelope::default(),
+            level_scale: Smoother::new(SmoothingStyle::Linear(LEVEL_SCALE_SMOOTHING_MS)),
+            rate_scale: 1.0,
+        }
+    }
+}
+
+impl Operator {
+    /// Render the next sample for this operator and advance its phase. `modulation` is an offset
+    /// added to the phase, in cycles. `feedback` is the amount the operator's own output is added to
+    /// its phase. `amplitude` is the operator's output level multiplied by its envelope.
+    pub fn next_sample(
+        &mut self,
+        waveform: Waveform,
+        phase_delta: f32,
+        modulation: f32,
+        feedback: f32,
+        amplitude: f32,
+    ) -> f32 {
+        // Feeding back only the last sample causes the output to oscillate between two values at
+        // high feedback amounts, also known as hunting. Like the Yamaha chips, we average the last
+        // two samples to dampen this.
+        let feedback = feedback * (self.output + self.previous_output) * 0.5;
+        let sample = waveform.sample(self.phase + modulation + feedback) * amplitude;
+
+        self.phase = (self.phase + phase_delta).fract();
+        self.previous_output = self.output;
+        self.output = sample;
+
+        sample
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn feedback_averages_the_last_two_samples() {
+        let sine = |phase: f32| (phase * consts::TAU).sin();
+        let feedback = 0.8;
+        let mut operator = Operator {
+            phase: 0.1,
+            ..Operator::default()
+        };
+
+        // Without a phase increment only the feedback moves the phase
+        let first = operator.next_sample(Waveform::Sine, 0.0, 0.0, feedback, 1.0);
+        assert_eq!(first, sine(0.1));
+        let second = operator.next_sample(Waveform::Sine, 0.0, 0.0, feedback, 1.0);
+        assert_eq!(second, sine(0.1 + feedback * first * 0.5));
+        let third = operator.next_sample(Waveform::Sine, 0.0, 0.0, feedback, 1.0);
+        assert_eq!(third, sine(0.1 + feedback * (second + first) * 0.5));
+    }
+
+    #[test]
+    fn coarse_ratio_zero_is_half() {
+        assert_eq!(coarse_ratio(0), 0.5);
+        assert_eq!(coarse_ratio(1), 1.0);
+        assert_eq!(coarse_ratio(31), 31.0);
+    }
+
+    #[test]
+    fn ratio_frequencies_follow_the_note() {
+        assert_eq!(
+            operator_frequency(2.0, 0.5, 0.0, false, 440.0, 44_100.0),
+            (3.0, 0.0)
+        );
+        assert_eq!(
+            operator_frequency(0.5, 0.0, 1200.0, false, 440.0, 44_100.0),
+            (1.0, 0.0)
+        );
+        let (ratio, _) = operator_frequency(1.0, 0.0, -7.0, false, 440.0, 44_100.0);
+        assert!((ratio - 2.0f32.powf(-7.0 / 1200.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn fixed_frequencies_ignore_the_note() {
+        assert_eq!(
+            operator_frequency(2.0, 0.5, 0.0, true, 441.0, 44_100.0),
+            (0.0, 0.01)
+        );
+        assert_eq!(
+            operator_frequency(2.0, 0.5, -1200.0, true, 882.0, 44_100.0),
+            (0.0, 0.01)
+        );
+    }
+
+    #[test]
+    fn keyboard_level_scaling_is_neutral_at_the_breakpoint() {
+        for curve in [
+            ScalingCurve::NegativeLinear,
+            ScalingCurve::NegativeExponential,
+            ScalingCurve::PositiveExponential,
+            ScalingCurve::PositiveLinear,
+        ] {
+            assert_eq!(keyboard_level_scale(60, 60, curve, 1.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn keyboard_level_scaling_reaches_full_depth_after_four_octaves() {
+        for (note, breakpoint) in [(12, 60), (108, 60)] {
+            let scale = |curve| keyboard_level_scale(note, breakpoint, curve, 1.0);
+            assert_eq!(scale(ScalingCurve::NegativeLinear), 0.0);
+            assert_eq!(scale(ScalingCurve::NegativeExponential), 0.0);
+            assert_eq!(scale(ScalingCurve::PositiveExponential), 2.0);
+            assert_eq!(scale(ScalingCurve::PositiveLinear), 2.0);
+        }
+
+        // Two octaves out, the linear curves are halfway there while the exponential curves have
+        // only covered a fifth of the range
+        assert_eq!(
+            keyboard_level_scale(84, 60, ScalingCurve::NegativeLinear, 1.0),
+            0.5
+        );
+        assert!(
+            (keyboard_level_scale(84, 60, ScalingCurve::NegativeExponential, 1.0) - 0.8).abs()
+                < 1e-6
+        );
+        assert_eq!(
+            keyboard_level_scale(84, 60, ScalingCurve::PositiveLinear, 0.5),
+            1.25
+        );
+    }
+
+    #[test]
+    fn keyboard_level_scaling_is_clamped_at_the_extremes() {
+        assert_eq!(
+            keyboard_level_scale(127, 0, ScalingCurve::NegativeExponential, 1.0),
+            0.0
+        );
+        assert_eq!(
+            keyboard_level_scale(0, 127, ScalingCurve::PositiveLinear, 1.0),
+            2.0
+        );
+        assert_eq!(
+            keyboard_level_scale(0, 127, ScalingCurve::NegativeLinear, 0.0),
+            1.0
+        );
+    }
+
+    #[test]
+    fn rate_scaling_halves_envelope_times_every_two_octaves() {
+        assert_eq!(keyboard_rate_scale(0, 1.0), 1.0);
+        assert_eq!(keyboard_rate_scale(RATE_SCALING_REFERENCE_NOTE, 1.0), 1.0);
+        assert_eq!(
+            keyboard_rate_scale(RATE_SCALING_REFERENCE_NOTE + 24, 1.0),
+            0.5
+        );
+        assert_eq!(
+            keyboard_rate_scale(RATE_SCALING_REFERENCE_NOTE + 48, 0.5),
+            0.5
+        );
+        assert_eq!(keyboard_rate_scale(127, 0.0), 1.0);
+        assert!((keyboard_rate_scale(127, 1.0) - 2.0f32.powf(-106.0 / 24.0)).abs() < 1e-6);
+    }
+}
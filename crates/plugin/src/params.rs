@@ -1,4 +1,16 @@
-use crate::GAIN_POLY_MOD_ID;
+use crate::{
+    algorithm::Algorithm,
+    envelope::{
+        key_time_scale, velocity_time_scale, EnvelopeCurve, EnvelopeMode, EnvelopeSettings,
+        LoopMode, RateLevelSettings, RetriggerMode, Segment,
+    },
+    lfo::{LfoMode, LfoSettings, LfoShape, NUM_LFOS},
+    mod_matrix::{ModDestination, ModSlot, ModSource, NUM_MOD_SLOTS},
+    operator::{coarse_ratio, ScalingCurve, Waveform, NUM_OPERATORS},
+    poly_mod::{EnvelopeTarget, PolyModTarget},
+    tempo::NoteDivision,
+    velocity::VelocityCurve,
+};
 use nih_plug::prelude::*;
 use nih_plug_iced::IcedState;
 use std::sync::Arc;
@@ -10,18 +22,332 @@ pub struct FmSynthParams {
     /// A voice's gain. This can be polyphonically modulated.
     #[id = "gain"]
     pub gain: FloatParam,
-    /// The amplitude envelope attack time. This is the same for every voice.
-    #[id = "amp_atk"]
-    pub amp_attack_ms: FloatParam,
-    #[id = "amp_hol"]
-    pub amp_hold_ms: FloatParam,
-    #[id = "amp_dec"]
-    pub amp_decay_ms: FloatParam,
-    #[id = "amp_sus"]
-    pub amp_sustain_percentage: FloatParam,
-    /// The amplitude envelope release time. This is the same for every voice.
-    #[id = "amp_rel"]
-    pub amp_release_ms: FloatParam,
+    /// How the note's velocity is mapped to the voice's gain and to the operators' velocity
+    /// sensitivity.
+    #[id = "vel_curve"]
+    pub velocity_curve: EnumParam<VelocityCurve>,
+    /// Which operators modulate which, and which operators are summed into the output.
+    #[id = "algorithm"]
+    pub algorithm: EnumParam<Algorithm>,
+    /// Whether new notes reuse sounding voices, and whether that restarts their envelopes.
+    #[id = "retrigger"]
+    pub retrigger: EnumParam<RetriggerMode>,
+    /// How far the pitch bend wheel bends up, in semitones.
+    #[id = "bend_up"]
+    pub pitch_bend_up: IntParam,
+    /// How far the pitch bend wheel bends down, in semitones.
+    #[id = "bend_down"]
+    pub pitch_bend_down: IntParam,
+    /// The settings for every operator in a voice. How the operators are connected is determined
+    /// by the algorithm.
+    #[nested(array, group = "Operator")]
+    pub operators: [OperatorParams; NUM_OPERATORS],
+    /// Bends the pitch of the operators that have the pitch envelope enabled.
+    #[nested(id_prefix = "pitch", group = "Pitch Envelope")]
+    pub pitch_envelope: PitchEnvelopeParams,
+    #[nested(array, group = "LFO")]
+    pub lfos: [LfoParams; NUM_LFOS],
+    /// A general purpose modulation source for the mod matrix. This can be polyphonically
+    /// modulated, which makes host modulation available to the mod matrix.
+    #[id = "macro"]
+    pub macro_amount: FloatParam,
+    /// Routes modulation sources to parameters.
+    #[nested(array, group = "Mod Matrix")]
+    pub mod_slots: [ModSlotParams; NUM_MOD_SLOTS],
+}
+
+#[derive(Params)]
+pub struct ModSlotParams {
+    #[id = "slot_src"]
+    pub source: EnumParam<ModSource>,
+    #[id = "slot_dst"]
+    pub destination: EnumParam<ModDestination>,
+    /// The operator for destinations that belong to a single operator.
+    #[id = "slot_op"]
+    pub operator: IntParam,
+    /// How far the source moves the destination's normalized value. Negative depths invert the
+    /// source.
+    #[id = "slot_depth"]
+    pub depth: FloatParam,
+}
+
+#[derive(Params)]
+pub struct LfoParams {
+    #[id = "lfo_shape"]
+    pub shape: EnumParam<LfoShape>,
+    /// Whether the LFO is shared by all voices or whether every voice gets its own.
+    #[id = "lfo_mode"]
+    pub mode: EnumParam<LfoMode>,
+    #[id = "lfo_rate"]
+    pub rate_hz: FloatParam,
+    /// Use `division` at the host's tempo for the length of one cycle instead of the rate.
+    #[id = "lfo_sync"]
+    pub sync: BoolParam,
+    #[id = "lfo_div"]
+    pub division: EnumParam<NoteDivision>,
+    /// How long the LFO stays silent after a note starts.
+    #[id = "lfo_delay"]
+    pub delay_ms: FloatParam,
+    /// How long the LFO takes to fade in after the delay.
+    #[id = "lfo_fade"]
+    pub fade_ms: FloatParam,
+    /// Restart the LFO's cycle for every note.
+    #[id = "lfo_key_sync"]
+    pub key_sync: BoolParam,
+    /// How far the LFO moves the pitch of every operator that's not in fixed frequency mode, in
+    /// semitones.
+    #[id = "lfo_pitch"]
+    pub pitch_depth: FloatParam,
+    /// How much the LFO lowers the voice's gain.
+    #[id = "lfo_gain"]
+    pub gain_depth: FloatParam,
+    /// How much the LFO lowers every operator's level, indexed by operator.
+    #[nested(array, group = "Level Depth")]
+    pub level_depths: [LfoLevelParams; NUM_OPERATORS],
+}
+
+#[derive(Params)]
+pub struct LfoLevelParams {
+    #[id = "lfo_level"]
+    pub depth: FloatParam,
+}
+
+#[derive(Params)]
+pub struct PitchEnvelopeParams {
+    /// How far the pitch moves in semitones when the envelope is at its maximum. Negative depths
+    /// bend the pitch down.
+    #[id = "depth"]
+    pub depth: FloatParam,
+    #[nested(id_prefix = "env", group = "Envelope")]
+    pub envelope: EnvelopeParams,
+}
+
+#[derive(Params)]
+pub struct OperatorParams {
+    /// The operator's frequency as a multiple of the note's frequency. 0 means a ratio of 0.5.
+    #[id = "coarse"]
+    pub coarse: IntParam,
+    /// Added to the coarse ratio as a fraction of it, so the ratio becomes `coarse * (1 + fine)`.
+    #[id = "fine"]
+    pub fine: FloatParam,
+    /// Detunes the operator in cents. This applies to both the ratio and the fixed frequency.
+    #[id = "detune"]
+    pub detune_cents: FloatParam,
+    /// Ignore the played note and use the fixed frequency instead of the ratio.
+    #[id = "fixed"]
+    pub fixed: BoolParam,
+    /// The operator's frequency when it's in fixed frequency mode.
+    #[id = "fixed_hz"]
+    pub fixed_frequency: FloatParam,
+    /// The shape of the operator's oscillator.
+    #[id = "wave"]
+    pub waveform: EnumParam<Waveform>,
+    /// The operator's output level. For modulators this sets the modulation depth.
+    #[id = "level"]
+    pub level: FloatParam,
+    /// How much the velocity lowers the operator's level for softer notes. At zero the operator's
+    /// level does not depend on the velocity.
+    #[id = "vel_sens"]
+    pub velocity_sensitivity: FloatParam,
+    /// How much the operator's own output is fed back into its phase.
+    #[id = "fb"]
+    pub feedback: FloatParam,
+    /// How much every operator modulates this operator's phase, indexed by the modulating
+    /// operator. This is added on top of the connections made by the algorithm. The entry for the
+    /// operator itself is added to the feedback amount.
+    #[nested(array, group = "Modulation")]
+    pub modulation: [ModulationParams; NUM_OPERATORS],
+    /// Whether the pitch envelope changes this operator's frequency.
+    #[id = "pitch_env"]
+    pub pitch_envelope: BoolParam,
+    /// The envelope for the operator's output level.
+    #[nested(id_prefix = "env", group = "Envelope")]
+    pub envelope: EnvelopeParams,
+    /// Changes the operator's output level and envelope times depending on the note.
+    #[nested(id_prefix = "ks", group = "Keyboard Scaling")]
+    pub keyboard_scaling: KeyboardScalingParams,
+}
+
+#[derive(Params)]
+pub struct ModulationParams {
+    /// The modulation depth for a single cell in the modulation matrix.
+    #[id = "mod"]
+    pub depth: FloatParam,
+}
+
+#[derive(Params)]
+pub struct KeyboardScalingParams {
+    /// The note where keyboard level scaling starts. Notes below this use the left depth and
+    /// curve, and notes above it use the right depth and curve.
+    #[id = "bp"]
+    pub breakpoint: IntParam,
+    #[id = "l_depth"]
+    pub left_depth: FloatParam,
+    #[id = "r_depth"]
+    pub right_depth: FloatParam,
+    #[id = "l_curve"]
+    pub left_curve: EnumParam<ScalingCurve>,
+    #[id = "r_curve"]
+    pub right_curve: EnumParam<ScalingCurve>,
+    /// How much shorter the envelope times get for higher notes.
+    #[id = "rate"]
+    pub rate_scaling: FloatParam,
+}
+
+#[derive(Params)]
+pub struct EnvelopeParams {
+    /// Whether this uses the AHDSR parameters or the rate and level parameters.
+    #[id = "mode"]
+    pub mode: EnumParam<EnvelopeMode>,
+    /// The envelope's attack time in milliseconds.
+    #[id = "atk"]
+    pub attack_ms: FloatParam,
+    #[id = "hol"]
+    pub hold_ms: FloatParam,
+    #[id = "dec"]
+    pub decay_ms: FloatParam,
+    #[id = "sus"]
+    pub sustain_percentage: FloatParam,
+    /// The envelope's release time in milliseconds.
+    #[id = "rel"]
+    pub release_ms: FloatParam,
+    #[id = "atk_crv"]
+    pub attack_curve: EnumParam<EnvelopeCurve>,
+    /// The exponent for the attack stage when it uses the power curve.
+    #[id = "atk_pow"]
+    pub attack_power: FloatParam,
+    #[id = "dec_crv"]
+    pub decay_curve: EnumParam<EnvelopeCurve>,
+    #[id = "dec_pow"]
+    pub decay_power: FloatParam,
+    #[id = "rel_crv"]
+    pub release_curve: EnumParam<EnvelopeCurve>,
+    #[id = "rel_pow"]
+    pub release_power: FloatParam,
+    /// The time the rate/level envelope's first stage takes to move across the full range.
+    #[id = "r1"]
+    pub rate_1_ms: FloatParam,
+    #[id = "r2"]
+    pub rate_2_ms: FloatParam,
+    #[id = "r3"]
+    pub rate_3_ms: FloatParam,
+    #[id = "r4"]
+    pub rate_4_ms: FloatParam,
+    /// The level the rate/level envelope's first stage moves to.
+    #[id = "l1"]
+    pub level_1_percentage: FloatParam,
+    #[id = "l2"]
+    pub level_2_percentage: FloatParam,
+    /// The rate/level envelope sustains at this level.
+    #[id = "l3"]
+    pub level_3_percentage: FloatParam,
+    /// The rate/level envelope starts at and returns to this level.
+    #[id = "l4"]
+    pub level_4_percentage: FloatParam,
+    #[id = "loop"]
+    pub loop_mode: EnumParam<LoopMode>,
+    /// The first looping stage. These are the attack, hold and decay stages, or the first three
+    /// rate/level stages.
+    #[id = "loop_start"]
+    pub loop_start: IntParam,
+    #[id = "loop_end"]
+    pub loop_end: IntParam,
+    /// Whether one pass through the looping stages lasts `loop_division` at the host's tempo.
+    #[id = "loop_sync"]
+    pub loop_sync: BoolParam,
+    #[id = "loop_div"]
+    pub loop_division: EnumParam<NoteDivision>,
+    /// Velocity and key tracking for the attack time and the first rate/level stage.
+    #[nested(id_prefix = "atk", group = "Attack Tracking")]
+    pub attack_tracking: TimeTrackingParams,
+    #[nested(id_prefix = "hol", group = "Hold Tracking")]
+    pub hold_tracking: TimeTrackingParams,
+    /// Velocity and key tracking for the decay time and the second and third rate/level stages.
+    #[nested(id_prefix = "dec", group = "Decay Tracking")]
+    pub decay_tracking: TimeTrackingParams,
+    /// Velocity and key tracking for the release time and the last rate/level stage.
+    #[nested(id_prefix = "rel", group = "Release Tracking")]
+    pub release_tracking: TimeTrackingParams,
+}
+
+/// The values of the envelope's continuous parameters. These can be modulated per voice before
+/// they're turned into [`EnvelopeSettings`]. The attack and hold times, the first rate, the levels
+/// other than the third level and the attack and hold tracking are only used when a note starts,
+/// the rest is read again for every block while the note plays.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EnvelopeValues {
+    pub decay_ms: f32,
+    pub decay_power: f32,
+    pub sustain_percentage: f32,
+    pub release_ms: f32,
+    pub release_power: f32,
+    pub rate_2_ms: f32,
+    pub rate_3_ms: f32,
+    pub rate_4_ms: f32,
+    pub level_3_percentage: f32,
+    pub attack_ms: f32,
+    pub attack_power: f32,
+    pub hold_ms: f32,
+    pub rate_1_ms: f32,
+    pub level_1_percentage: f32,
+    pub level_2_percentage: f32,
+    pub level_4_percentage: f32,
+    /// The velocity and key tracking amounts for the attack time.
+    pub attack_tracking: [f32; 2],
+    pub hold_tracking: [f32; 2],
+    pub decay_tracking: [f32; 2],
+    pub release_tracking: [f32; 2],
+}
+
+impl EnvelopeValues {
+    /// Create the values by calling `value` for each of the parameters.
+    pub fn from_fn(value: impl Fn(EnvelopeTarget) -> f32) -> Self {
+        Self {
+            decay_ms: value(EnvelopeTarget::Decay),
+            decay_power: value(EnvelopeTarget::DecayPower),
+            sustain_percentage: value(EnvelopeTarget::Sustain),
+            release_ms: value(EnvelopeTarget::Release),
+            release_power: value(EnvelopeTarget::ReleasePower),
+            rate_2_ms: value(EnvelopeTarget::Rate2),
+            rate_3_ms: value(EnvelopeTarget::Rate3),
+            rate_4_ms: value(EnvelopeTarget::Rate4),
+            level_3_percentage: value(EnvelopeTarget::Level3),
+            attack_ms: value(EnvelopeTarget::Attack),
+            attack_power: value(EnvelopeTarget::AttackPower),
+            hold_ms: value(EnvelopeTarget::Hold),
+            rate_1_ms: value(EnvelopeTarget::Rate1),
+            level_1_percentage: value(EnvelopeTarget::Level1),
+            level_2_percentage: value(EnvelopeTarget::Level2),
+            level_4_percentage: value(EnvelopeTarget::Level4),
+            attack_tracking: [
+                value(EnvelopeTarget::AttackVelocityTracking),
+                value(EnvelopeTarget::AttackKeyTracking),
+            ],
+            hold_tracking: [
+                value(EnvelopeTarget::HoldVelocityTracking),
+                value(EnvelopeTarget::HoldKeyTracking),
+            ],
+            decay_tracking: [
+                value(EnvelopeTarget::DecayVelocityTracking),
+                value(EnvelopeTarget::DecayKeyTracking),
+            ],
+            release_tracking: [
+                value(EnvelopeTarget::ReleaseVelocityTracking),
+                value(EnvelopeTarget::ReleaseKeyTracking),
+            ],
+        }
+    }
+}
+
+/// Changes one of the envelope's times based on the note's velocity and key.
+#[derive(Params)]
+pub struct TimeTrackingParams {
+    /// Positive amounts make harder hits faster, negative amounts make them slower.
+    #[id = "vel"]
+    pub velocity: FloatParam,
+    /// Positive amounts make higher notes faster, negative amounts make them slower.
+    #[id = "key"]
+    pub key: FloatParam,
 }
 
 impl Default for FmSynthParams {
@@ -41,13 +367,430 @@ impl Default for FmSynthParams {
             // This enables polyphonic mdoulation for this parameter by representing all related
             // events with this ID. After enabling this, the plugin **must** start sending
             // `VoiceTerminated` events to the host whenever a voice has ended.
-            .with_poly_modulation_id(GAIN_POLY_MOD_ID)
+            .with_poly_modulation_id(PolyModTarget::Gain.id())
             .with_smoother(SmoothingStyle::Logarithmic(5.0))
             .with_unit(" dB")
             .with_value_to_string(formatters::v2s_f32_gain_to_db(2))
             .with_string_to_value(formatters::s2v_f32_gain_to_db()),
-            amp_attack_ms: FloatParam::new(
-                "Attack",
+            velocity_curve: EnumParam::new("Velocity Curve", VelocityCurve::SquareRoot),
+            algorithm: EnumParam::new("Algorithm", Algorithm::A1),
+            retrigger: EnumParam::new("Retrigger", RetriggerMode::Reset),
+            pitch_bend_up: IntParam::new("Pitch Bend Up", 2, IntRange::Linear { min: 0, max: 48 })
+                .with_unit(" st"),
+            pitch_bend_down: IntParam::new(
+                "Pitch Bend Down",
+                2,
+                IntRange::Linear { min: 0, max: 48 },
+            )
+            .with_unit(" st"),
+            operators: std::array::from_fn(OperatorParams::new),
+            pitch_envelope: PitchEnvelopeParams::default(),
+            lfos: std::array::from_fn(LfoParams::new),
+            macro_amount: FloatParam::new("Macro", 0.0, FloatRange::Linear { min: 0.0, max: 1.0 })
+                .with_poly_modulation_id(PolyModTarget::Macro.id())
+                .with_smoother(SmoothingStyle::Linear(5.0))
+                .with_unit(" %")
+                .with_value_to_string(formatters::v2s_f32_percentage(1))
+                .with_string_to_value(formatters::s2v_f32_percentage()),
+            mod_slots: std::array::from_fn(ModSlotParams::new),
+        }
+    }
+}
+
+impl FmSynthParams {
+    /// The parameter belonging to a poly modulation target.
+    pub fn poly_mod_param(&self, target: PolyModTarget) -> &FloatParam {
+        match target {
+            PolyModTarget::Gain => &self.gain,
+            PolyModTarget::Macro => &self.macro_amount,
+            PolyModTarget::PitchEnvelopeDepth => &self.pitch_envelope.depth,
+            PolyModTarget::PitchEnvelope(target) => self.pitch_envelope.envelope.param(target),
+            PolyModTarget::LfoPitchDepth(lfo_idx) => &self.lfos[lfo_idx].pitch_depth,
+            PolyModTarget::LfoGainDepth(lfo_idx) => &self.lfos[lfo_idx].gain_depth,
+            PolyModTarget::LfoRate(lfo_idx) => &self.lfos[lfo_idx].rate_hz,
+            PolyModTarget::LfoDelay(lfo_idx) => &self.lfos[lfo_idx].delay_ms,
+            PolyModTarget::LfoFade(lfo_idx) => &self.lfos[lfo_idx].fade_ms,
+            PolyModTarget::LfoLevelDepth(lfo_idx, op_idx) => {
+                &self.lfos[lfo_idx].level_depths[op_idx].depth
+            }
+            PolyModTarget::ModSlotDepth(slot_idx) => &self.mod_slots[slot_idx].depth,
+            PolyModTarget::Fine(op_idx) => &self.operators[op_idx].fine,
+            PolyModTarget::Detune(op_idx) => &self.operators[op_idx].detune_cents,
+            PolyModTarget::FixedFrequency(op_idx) => &self.operators[op_idx].fixed_frequency,
+            PolyModTarget::Level(op_idx) => &self.operators[op_idx].level,
+            PolyModTarget::Feedback(op_idx) => &self.operators[op_idx].feedback,
+            PolyModTarget::VelocitySensitivity(op_idx) => {
+                &self.operators[op_idx].velocity_sensitivity
+            }
+            PolyModTarget::LeftDepth(op_idx) => &self.operators[op_idx].keyboard_scaling.left_depth,
+            PolyModTarget::RightDepth(op_idx) => {
+                &self.operators[op_idx].keyboard_scaling.right_depth
+            }
+            PolyModTarget::RateScaling(op_idx) => {
+                &self.operators[op_idx].keyboard_scaling.rate_scaling
+            }
+            PolyModTarget::OperatorEnvelope(op_idx, target) => {
+                self.operators[op_idx].envelope.param(target)
+            }
+            PolyModTarget::Modulation(op_idx, modulator_idx) => {
+                &self.operators[op_idx].modulation[modulator_idx].depth
+            }
+        }
+    }
+}
+
+impl Default for PitchEnvelopeParams {
+    fn default() -> Self {
+        Self {
+            depth: FloatParam::new(
+                "Pitch Envelope Depth",
+                0.0,
+                FloatRange::Linear {
+                    min: -48.0,
+                    max: 48.0,
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::PitchEnvelopeDepth.id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_step_size(0.01)
+            .with_unit(" st"),
+            envelope: EnvelopeParams::new("Pitch", PolyModTarget::PitchEnvelope),
+        }
+    }
+}
+
+impl ModSlotParams {
+    /// Create the parameters for the mod matrix slot at `index`. Slots are empty by default.
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        Self {
+            source: EnumParam::new(format!("Slot {number} Source"), ModSource::None),
+            destination: EnumParam::new(format!("Slot {number} Destination"), ModDestination::None),
+            operator: IntParam::new(
+                format!("Slot {number} Operator"),
+                1,
+                IntRange::Linear {
+                    min: 1,
+                    max: NUM_OPERATORS as i32,
+                },
+            ),
+            depth: FloatParam::new(
+                format!("Slot {number} Depth"),
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::ModSlotDepth(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+
+    /// The slot's current routing.
+    pub fn slot(&self) -> ModSlot {
+        ModSlot {
+            source: self.source.value(),
+            destination: self.destination.value(),
+            operator: self.operator.value() as usize - 1,
+        }
+    }
+}
+
+impl LfoParams {
+    /// Create the parameters for the LFO at `index`. The LFOs don't modulate anything by default.
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        Self {
+            shape: EnumParam::new(format!("LFO {number} Shape"), LfoShape::Sine),
+            mode: EnumParam::new(format!("LFO {number} Mode"), LfoMode::Global),
+            rate_hz: FloatParam::new(
+                format!("LFO {number} Rate"),
+                5.0,
+                FloatRange::Skewed {
+                    min: 0.01,
+                    max: 50.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::LfoRate(index).id())
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            sync: BoolParam::new(format!("LFO {number} Sync"), false),
+            division: EnumParam::new(format!("LFO {number} Division"), NoteDivision::Quarter),
+            delay_ms: FloatParam::new(
+                format!("LFO {number} Delay"),
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::LfoDelay(index).id())
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            fade_ms: FloatParam::new(
+                format!("LFO {number} Fade In"),
+                0.0,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 5000.0,
+                    factor: FloatRange::skew_factor(-1.0),
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::LfoFade(index).id())
+            .with_step_size(0.1)
+            .with_unit(" ms"),
+            key_sync: BoolParam::new(format!("LFO {number} Key Sync"), false),
+            pitch_depth: FloatParam::new(
+                format!("LFO {number} Pitch Depth"),
+                0.0,
+                FloatRange::Linear {
+                    min: -12.0,
+                    max: 12.0,
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::LfoPitchDepth(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_step_size(0.01)
+            .with_unit(" st"),
+            gain_depth: FloatParam::new(
+                format!("LFO {number} Gain Depth"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::LfoGainDepth(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            level_depths: std::array::from_fn(|op_idx| LfoLevelParams::new(index, op_idx)),
+        }
+    }
+
+    /// The LFO's current settings. `tempo` is used when the rate is synced to the host's tempo.
+    pub fn settings(&self, tempo: f64) -> LfoSettings {
+        LfoSettings {
+            shape: self.shape.value(),
+            rate_hz: if self.sync.value() {
+                1000.0 / self.division.value().duration_ms(tempo)
+            } else {
+                self.rate_hz.value()
+            },
+            delay_ms: self.delay_ms.value(),
+            fade_ms: self.fade_ms.value(),
+        }
+    }
+}
+
+impl LfoLevelParams {
+    fn new(lfo_index: usize, op_index: usize) -> Self {
+        Self {
+            depth: FloatParam::new(
+                format!("LFO {} Op {} Level Depth", lfo_index + 1, op_index + 1),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::LfoLevelDepth(lfo_index, op_index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+impl OperatorParams {
+    /// Create the parameters for the operator at `index`. Only the first two operators are audible
+    /// by default, which with the first algorithm results in a simple two operator FM patch.
+    fn new(index: usize) -> Self {
+        let number = index + 1;
+
+        Self {
+            coarse: IntParam::new(
+                format!("Op {number} Coarse"),
+                1,
+                IntRange::Linear { min: 0, max: 32 },
+            )
+            .with_value_to_string(Arc::new(|value| coarse_ratio(value).to_string()))
+            .with_string_to_value(Arc::new(|string| {
+                let ratio = string.trim().parse::<f32>().ok()?;
+                Some(if ratio < 1.0 { 0 } else { ratio as i32 })
+            })),
+            fine: FloatParam::new(
+                format!("Op {number} Fine"),
+                0.0,
+                FloatRange::Linear {
+                    min: 0.0,
+                    max: 0.99,
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::Fine(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            detune_cents: FloatParam::new(
+                format!("Op {number} Detune"),
+                0.0,
+                FloatRange::Linear {
+                    min: -100.0,
+                    max: 100.0,
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::Detune(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_step_size(0.1)
+            .with_unit(" ct"),
+            fixed: BoolParam::new(format!("Op {number} Fixed"), false),
+            fixed_frequency: FloatParam::new(
+                format!("Op {number} Fixed Frequency"),
+                440.0,
+                FloatRange::Skewed {
+                    min: 1.0,
+                    max: 20_000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+            .with_poly_modulation_id(PolyModTarget::FixedFrequency(index).id())
+            .with_smoother(SmoothingStyle::Logarithmic(5.0))
+            .with_unit(" Hz")
+            .with_value_to_string(formatters::v2s_f32_hz_then_khz(2))
+            .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            waveform: EnumParam::new(format!("Op {number} Waveform"), Waveform::Sine),
+            level: FloatParam::new(
+                format!("Op {number} Level"),
+                match index {
+                    0 => 1.0,
+                    1 => 0.5,
+                    _ => 0.0,
+                },
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::Level(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            velocity_sensitivity: FloatParam::new(
+                format!("Op {number} Velocity Sensitivity"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::VelocitySensitivity(index).id())
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            feedback: FloatParam::new(
+                format!("Op {number} Feedback"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::Feedback(index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            modulation: std::array::from_fn(|modulator_index| {
+                ModulationParams::new(modulator_index, index)
+            }),
+            pitch_envelope: BoolParam::new(format!("Op {number} Pitch Envelope"), true),
+            envelope: EnvelopeParams::new(&format!("Op {number}"), |target| {
+                PolyModTarget::OperatorEnvelope(index, target)
+            }),
+            keyboard_scaling: KeyboardScalingParams::new(&format!("Op {number}"), index),
+        }
+    }
+}
+
+impl ModulationParams {
+    /// Create the modulation depth for the operator at `modulator_index` modulating the operator at
+    /// `target_index`.
+    fn new(modulator_index: usize, target_index: usize) -> Self {
+        Self {
+            depth: FloatParam::new(
+                format!("Op {} to Op {}", modulator_index + 1, target_index + 1),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::Modulation(target_index, modulator_index).id())
+            .with_smoother(SmoothingStyle::Linear(5.0))
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+impl KeyboardScalingParams {
+    /// Create the keyboard scaling parameters for the operator at `op_index`. These don't change
+    /// anything by default. Like the envelope, the parameter names are prefixed with `name_prefix`.
+    fn new(name_prefix: &str, op_index: usize) -> Self {
+        Self {
+            breakpoint: IntParam::new(
+                format!("{name_prefix} Breakpoint"),
+                60,
+                IntRange::Linear { min: 0, max: 127 },
+            )
+            .with_value_to_string(formatters::v2s_i32_note_formatter())
+            .with_string_to_value(formatters::s2v_i32_note_formatter()),
+            left_depth: FloatParam::new(
+                format!("{name_prefix} Left Depth"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::LeftDepth(op_index).id())
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            right_depth: FloatParam::new(
+                format!("{name_prefix} Right Depth"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::RightDepth(op_index).id())
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            left_curve: EnumParam::new(
+                format!("{name_prefix} Left Curve"),
+                ScalingCurve::NegativeLinear,
+            ),
+            right_curve: EnumParam::new(
+                format!("{name_prefix} Right Curve"),
+                ScalingCurve::NegativeLinear,
+            ),
+            rate_scaling: FloatParam::new(
+                format!("{name_prefix} Rate Scaling"),
+                0.0,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            )
+            .with_poly_modulation_id(PolyModTarget::RateScaling(op_index).id())
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(1))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+impl EnvelopeParams {
+    /// Create the parameters for an envelope. The parameter names are prefixed with `name_prefix`
+    /// so they can be told apart in the host, and `poly_mod_target` creates the poly modulation
+    /// targets for the parameters in [`EnvelopeValues`].
+    fn new(name_prefix: &str, poly_mod_target: impl Fn(EnvelopeTarget) -> PolyModTarget) -> Self {
+        let poly_mod_id = |target| poly_mod_target(target).id();
+
+        Self {
+            mode: EnumParam::new(format!("{name_prefix} Envelope Mode"), EnvelopeMode::Ahdsr),
+            attack_ms: FloatParam::new(
+                format!("{name_prefix} Attack"),
                 200.0,
                 FloatRange::Skewed {
                     min: 0.0,
@@ -55,13 +798,16 @@ impl Default for FmSynthParams {
                     factor: FloatRange::skew_factor(-1.0),
                 },
             )
-            // These parameters are global (and they cannot be changed once the voice has started).
-            // They also don't need any smoothing themselves because they affect smoothing
-            // coefficients.
+            // The attack and hold times are only read when a voice starts, while changes to the
+            // decay, sustain and release also affect voices that are already playing. All of them
+            // can be polyphonically modulated.
+            // They don't need any smoothing themselves because the envelope smooths changes to
+            // its targets.
+            .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Attack))
             .with_step_size(0.1)
             .with_unit(" ms"),
-            amp_release_ms: FloatParam::new(
-                "Release",
+            release_ms: FloatParam::new(
+                format!("{name_prefix} Release"),
                 100.0,
                 FloatRange::Skewed {
                     min: 0.0,
@@ -69,10 +815,11 @@ impl Default for FmSynthParams {
                     factor: FloatRange::skew_factor(-1.0),
                 },
             )
+            .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Release))
             .with_step_size(0.1)
             .with_unit(" ms"),
-            amp_decay_ms: FloatParam::new(
-                "Decay",
+            decay_ms: FloatParam::new(
+                format!("{name_prefix} Decay"),
                 100.0,
                 FloatRange::Skewed {
                     min: 0.0,
@@ -80,20 +827,22 @@ impl Default for FmSynthParams {
                     factor: FloatRange::skew_factor(-1.0),
                 },
             )
+            .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Decay))
             .with_step_size(0.1)
             .with_unit(" ms"),
-            amp_sustain_percentage: FloatParam::new(
-                "Sustain",
+            sustain_percentage: FloatParam::new(
+                format!("{name_prefix} Sustain"),
                 90.0,
                 FloatRange::Linear {
                     min: 0.0,
                     max: 100.0,
                 },
             )
+            .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Sustain))
             .with_step_size(0.1)
             .with_unit(" %"),
-            amp_hold_ms: FloatParam::new(
-                "Hold",
+            hold_ms: FloatParam::new(
+                format!("{name_prefix} Hold"),
                 100.0,
                 FloatRange::Skewed {
                     min: 0.0,
@@ -101,8 +850,258 @@ impl Default for FmSynthParams {
                     factor: FloatRange::skew_factor(-1.0),
                 },
             )
+            .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Hold))
             .with_step_size(0.1)
             .with_unit(" ms"),
+            attack_curve: EnumParam::new(
+                format!("{name_prefix} Attack Curve"),
+                EnvelopeCurve::Exponential,
+            ),
+            attack_power: new_power_param(&format!("{name_prefix} Attack Power"))
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::AttackPower)),
+            decay_curve: EnumParam::new(
+                format!("{name_prefix} Decay Curve"),
+                EnvelopeCurve::Exponential,
+            ),
+            decay_power: new_power_param(&format!("{name_prefix} Decay Power"))
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::DecayPower)),
+            release_curve: EnumParam::new(
+                format!("{name_prefix} Release Curve"),
+                EnvelopeCurve::Exponential,
+            ),
+            release_power: new_power_param(&format!("{name_prefix} Release Power"))
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::ReleasePower)),
+            rate_1_ms: new_rate_param(&format!("{name_prefix} Rate 1"), 200.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Rate1)),
+            rate_2_ms: new_rate_param(&format!("{name_prefix} Rate 2"), 1000.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Rate2)),
+            rate_3_ms: new_rate_param(&format!("{name_prefix} Rate 3"), 1000.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Rate3)),
+            rate_4_ms: new_rate_param(&format!("{name_prefix} Rate 4"), 100.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Rate4)),
+            level_1_percentage: new_level_param(&format!("{name_prefix} Level 1"), 100.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Level1)),
+            level_2_percentage: new_level_param(&format!("{name_prefix} Level 2"), 90.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Level2)),
+            level_3_percentage: new_level_param(&format!("{name_prefix} Level 3"), 90.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Level3)),
+            level_4_percentage: new_level_param(&format!("{name_prefix} Level 4"), 0.0)
+                .with_poly_modulation_id(poly_mod_id(EnvelopeTarget::Level4)),
+            loop_mode: EnumParam::new(format!("{name_prefix} Loop Mode"), LoopMode::Off),
+            loop_start: IntParam::new(
+                format!("{name_prefix} Loop Start"),
+                1,
+                IntRange::Linear { min: 1, max: 3 },
+            ),
+            loop_end: IntParam::new(
+                format!("{name_prefix} Loop End"),
+                3,
+                IntRange::Linear { min: 1, max: 3 },
+            ),
+            loop_sync: BoolParam::new(format!("{name_prefix} Loop Sync"), false),
+            loop_division: EnumParam::new(
+                format!("{name_prefix} Loop Division"),
+                NoteDivision::Quarter,
+            ),
+            attack_tracking: TimeTrackingParams::new(
+                &format!("{name_prefix} Attack"),
+                poly_mod_id(EnvelopeTarget::AttackVelocityTracking),
+                poly_mod_id(EnvelopeTarget::AttackKeyTracking),
+            ),
+            hold_tracking: TimeTrackingParams::new(
+                &format!("{name_prefix} Hold"),
+                poly_mod_id(EnvelopeTarget::HoldVelocityTracking),
+                poly_mod_id(EnvelopeTarget::HoldKeyTracking),
+            ),
+            decay_tracking: TimeTrackingParams::new(
+                &format!("{name_prefix} Decay"),
+                poly_mod_id(EnvelopeTarget::DecayVelocityTracking),
+                poly_mod_id(EnvelopeTarget::DecayKeyTracking),
+            ),
+            release_tracking: TimeTrackingParams::new(
+                &format!("{name_prefix} Release"),
+                poly_mod_id(EnvelopeTarget::ReleaseVelocityTracking),
+                poly_mod_id(EnvelopeTarget::ReleaseKeyTracking),
+            ),
+        }
+    }
+
+    /// The envelope's settings for a note, with the values of the continuous parameters taken from
+    /// `values`. These get copied into a voice's envelope when it starts. The times are scaled by
+    /// the note's `velocity` and key, and `tempo` is used for tempo synced loops.
+    pub fn settings(
+        &self,
+        tempo: f64,
+        velocity: f32,
+        note: u8,
+        values: &EnvelopeValues,
+    ) -> EnvelopeSettings {
+        let loop_start = self.loop_start.value();
+        let loop_end = self.loop_end.value();
+        let attack_scale = time_scale(values.attack_tracking, velocity, note);
+        let decay_scale = time_scale(values.decay_tracking, velocity, note);
+        let release_scale = time_scale(values.release_tracking, velocity, note);
+
+        EnvelopeSettings {
+            mode: self.mode.value(),
+            attack: Segment {
+                duration_ms: values.attack_ms * attack_scale,
+                curve: self.attack_curve.value(),
+                power: values.attack_power,
+            },
+            hold_ms: values.hold_ms * time_scale(values.hold_tracking, velocity, note),
+            decay: Segment {
+                duration_ms: values.decay_ms * decay_scale,
+                curve: self.decay_curve.value(),
+                power: values.decay_power,
+            },
+            sustain: values.sustain_percentage / 100.0,
+            release: Segment {
+                duration_ms: values.release_ms * release_scale,
+                curve: self.release_curve.value(),
+                power: values.release_power,
+            },
+            rate_level: RateLevelSettings {
+                rates_ms: [
+                    values.rate_1_ms * attack_scale,
+                    values.rate_2_ms * decay_scale,
+                    values.rate_3_ms * decay_scale,
+                    values.rate_4_ms * release_scale,
+                ],
+                levels: [
+                    values.level_1_percentage / 100.0,
+                    values.level_2_percentage / 100.0,
+                    values.level_3_percentage / 100.0,
+                    values.level_4_percentage / 100.0,
+                ],
+            },
+            loop_mode: self.loop_mode.value(),
+            loop_start: (loop_start.min(loop_end) - 1) as usize,
+            loop_end: (loop_start.max(loop_end) - 1) as usize,
+            loop_duration_ms: self
+                .loop_sync
+                .value()
+                .then(|| self.loop_division.value().duration_ms(tempo)),
+        }
+    }
+
+    /// The parameter belonging to one of the envelope's poly modulation targets.
+    pub fn param(&self, target: EnvelopeTarget) -> &FloatParam {
+        match target {
+            EnvelopeTarget::Decay => &self.decay_ms,
+            EnvelopeTarget::DecayPower => &self.decay_power,
+            EnvelopeTarget::Sustain => &self.sustain_percentage,
+            EnvelopeTarget::Release => &self.release_ms,
+            EnvelopeTarget::ReleasePower => &self.release_power,
+            EnvelopeTarget::Rate2 => &self.rate_2_ms,
+            EnvelopeTarget::Rate3 => &self.rate_3_ms,
+            EnvelopeTarget::Rate4 => &self.rate_4_ms,
+            EnvelopeTarget::Level3 => &self.level_3_percentage,
+            EnvelopeTarget::Attack => &self.attack_ms,
+            EnvelopeTarget::AttackPower => &self.attack_power,
+            EnvelopeTarget::Hold => &self.hold_ms,
+            EnvelopeTarget::Rate1 => &self.rate_1_ms,
+            EnvelopeTarget::Level1 => &self.level_1_percentage,
+            EnvelopeTarget::Level2 => &self.level_2_percentage,
+            EnvelopeTarget::Level4 => &self.level_4_percentage,
+            EnvelopeTarget::AttackVelocityTracking => &self.attack_tracking.velocity,
+            EnvelopeTarget::AttackKeyTracking => &self.attack_tracking.key,
+            EnvelopeTarget::HoldVelocityTracking => &self.hold_tracking.velocity,
+            EnvelopeTarget::HoldKeyTracking => &self.hold_tracking.key,
+            EnvelopeTarget::DecayVelocityTracking => &self.decay_tracking.velocity,
+            EnvelopeTarget::DecayKeyTracking => &self.decay_tracking.key,
+            EnvelopeTarget::ReleaseVelocityTracking => &self.release_tracking.velocity,
+            EnvelopeTarget::ReleaseKeyTracking => &self.release_tracking.key,
         }
     }
+
+    /// The envelope's release time in milliseconds for a note, with the release time and its
+    /// tracking taken from `values`. This is read again when the note is released.
+    pub fn release_ms(&self, velocity: f32, note: u8, values: &EnvelopeValues) -> f32 {
+        values.release_ms * time_scale(values.release_tracking, velocity, note)
+    }
+}
+
+impl TimeTrackingParams {
+    /// Create the tracking parameters for the envelope time named `name_prefix`, with the poly
+    /// modulation IDs for the velocity and key tracking amounts. These don't change anything by
+    /// default.
+    fn new(name_prefix: &str, velocity_poly_mod_id: u32, key_poly_mod_id: u32) -> Self {
+        Self {
+            velocity: FloatParam::new(
+                format!("{name_prefix} Velocity Tracking"),
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_poly_modulation_id(velocity_poly_mod_id)
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+            key: FloatParam::new(
+                format!("{name_prefix} Key Tracking"),
+                0.0,
+                FloatRange::Linear {
+                    min: -1.0,
+                    max: 1.0,
+                },
+            )
+            .with_poly_modulation_id(key_poly_mod_id)
+            .with_unit(" %")
+            .with_value_to_string(formatters::v2s_f32_percentage(0))
+            .with_string_to_value(formatters::s2v_f32_percentage()),
+        }
+    }
+}
+
+/// The factor an envelope time is multiplied by for a note, with the velocity and key tracking
+/// amounts in `tracking`.
+fn time_scale([velocity_amount, key_amount]: [f32; 2], velocity: f32, note: u8) -> f32 {
+    velocity_time_scale(velocity, velocity_amount) * key_time_scale(note, key_amount)
+}
+
+/// Create one of the rate/level envelope's rates. These are expressed as the time it takes to move
+/// between 0% and 100%, so rates near the DX7's slowest rate of 38 seconds fit in the range.
+fn new_rate_param(name: &str, default_ms: f32) -> FloatParam {
+    FloatParam::new(
+        name,
+        default_ms,
+        FloatRange::Skewed {
+            min: 0.0,
+            max: 40_000.0,
+            factor: FloatRange::skew_factor(-2.0),
+        },
+    )
+    .with_step_size(0.1)
+    .with_unit(" ms")
+}
+
+/// Create one of the rate/level envelope's levels.
+fn new_level_param(name: &str, default_percentage: f32) -> FloatParam {
+    FloatParam::new(
+        name,
+        default_percentage,
+        FloatRange::Linear {
+            min: 0.0,
+            max: 100.0,
+        },
+    )
+    .with_step_size(0.1)
+    .with_unit(" %")
+}
+
+/// Create the exponent parameter for an envelope stage's power curve.
+fn new_power_param(name: &str) -> FloatParam {
+    FloatParam::new(
+        name,
+        2.0,
+        FloatRange::Skewed {
+            min: 0.1,
+            max: 10.0,
+            factor: FloatRange::skew_factor(-1.0),
+        },
+    )
+    .with_step_size(0.01)
 }
@@ -0,0 +1,601 @@
+use crate::{
+    lfo::{LfoSettings, NUM_LFOS},
+    mod_matrix::NUM_MOD_SLOTS,
+    operator::NUM_OPERATORS,
+    params::{EnvelopeParams, EnvelopeValues, FmSynthParams},
+    MAX_BLOCK_SIZE,
+};
+use nih_plug::prelude::*;
+
+// Polyphonic modulation works by assigning integer IDs to parameters. Every continuous parameter
+// that shapes a voice gets its own ID. The envelopes' attack and hold stages, the rate/level
+// envelope's first rate and the levels other than its third level are only read when a note starts.
+// The envelopes are started right before the voice's first block is rendered, so modulation sent on
+// the same sample as the note still applies to them. Velocity sensitivity and keyboard scaling are
+// also read when a note starts, but modulating them recomputes the voice's levels and rates. The
+// IDs are laid out contiguously so the voices can store their modulation in a table indexed by the
+// ID, instead of every parameter needing its own code path.
+
+/// The first ID for the pitch envelope's parameters.
+const PITCH_ENVELOPE_IDS_START: usize = 3;
+/// The parameters in [`EnvelopeTarget`].
+const ENVELOPE_IDS_STRIDE: usize = EnvelopeTarget::ALL.len();
+/// The first ID for the LFOs' parameters.
+const LFO_IDS_START: usize = PITCH_ENVELOPE_IDS_START + ENVELOPE_IDS_STRIDE;
+/// The pitch depth, the gain depth, the rate, the delay, the fade in, and the level depth for every
+/// operator.
+const LFO_IDS_STRIDE: usize = 5 + NUM_OPERATORS;
+const MOD_SLOT_IDS_START: usize = LFO_IDS_START + NUM_LFOS * LFO_IDS_STRIDE;
+const OPERATOR_IDS_START: usize = MOD_SLOT_IDS_START + NUM_MOD_SLOTS;
+/// The fine ratio, detune, fixed frequency, level, feedback, velocity sensitivity, keyboard
+/// scaling depths and rate scaling come first, followed by the envelope's parameters.
+const OPERATOR_ENVELOPE_IDS_OFFSET: usize = 9;
+/// The modulation matrix depth for every modulator comes last.
+const OPERATOR_MODULATION_IDS_OFFSET: usize = OPERATOR_ENVELOPE_IDS_OFFSET + ENVELOPE_IDS_STRIDE;
+const OPERATOR_IDS_STRIDE: usize = OPERATOR_MODULATION_IDS_OFFSET + NUM_OPERATORS;
+
+/// The number of parameters that can be polyphonically modulated.
+pub const NUM_POLY_MOD_IDS: usize = OPERATOR_IDS_START + NUM_OPERATORS * OPERATOR_IDS_STRIDE;
+
+/// A parameter that can be polyphonically modulated. See [`FmSynthParams::poly_mod_param()`] for
+/// the parameter itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PolyModTarget {
+    Gain,
+    Macro,
+    PitchEnvelopeDepth,
+    /// The pitch depth of the LFO at the index.
+    LfoPitchDepth(usize),
+    /// The gain depth of the LFO at the index.
+    LfoGainDepth(usize),
+    /// The depth of the LFO at the first index for the level of the operator at the second index.
+    LfoLevelDepth(usize, usize),
+    LfoRate(usize),
+    LfoDelay(usize),
+    LfoFade(usize),
+    /// One of the pitch envelope's parameters.
+    PitchEnvelope(EnvelopeTarget),
+    /// The depth of the mod matrix slot at the index.
+    ModSlotDepth(usize),
+    Fine(usize),
+    Detune(usize),
+    FixedFrequency(usize),
+    Level(usize),
+    Feedback(usize),
+    VelocitySensitivity(usize),
+    LeftDepth(usize),
+    RightDepth(usize),
+    RateScaling(usize),
+    /// One of the envelope parameters of the operator at the index.
+    OperatorEnvelope(usize, EnvelopeTarget),
+    /// The modulation matrix depth for the operator at the first index, modulated by the operator
+    /// at the second index.
+    Modulation(usize, usize),
+}
+
+/// One of an envelope's continuous parameters. See [`EnvelopeValues`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EnvelopeTarget {
+    Decay,
+    DecayPower,
+    Sustain,
+    Release,
+    ReleasePower,
+    Rate2,
+    Rate3,
+    Rate4,
+    Level3,
+    Attack,
+    AttackPower,
+    Hold,
+    Rate1,
+    Level1,
+    Level2,
+    Level4,
+    AttackVelocityTracking,
+    AttackKeyTracking,
+    HoldVelocityTracking,
+    HoldKeyTracking,
+    DecayVelocityTracking,
+    DecayKeyTracking,
+    ReleaseVelocityTracking,
+    ReleaseKeyTracking,
+}
+
+impl PolyModTarget {
+    /// The parameter's poly modulation ID.
+    pub const fn id(self) -> u32 {
+        let id = match self {
+            PolyModTarget::Gain => 0,
+            PolyModTarget::Macro => 1,
+            PolyModTarget::PitchEnvelopeDepth => 2,
+            PolyModTarget::PitchEnvelope(target) => PITCH_ENVELOPE_IDS_START + target.index(),
+            PolyModTarget::LfoPitchDepth(lfo_idx) => LFO_IDS_START + lfo_idx * LFO_IDS_STRIDE,
+            PolyModTarget::LfoGainDepth(lfo_idx) => LFO_IDS_START + lfo_idx * LFO_IDS_STRIDE + 1,
+            PolyModTarget::LfoRate(lfo_idx) => LFO_IDS_START + lfo_idx * LFO_IDS_STRIDE + 2,
+            PolyModTarget::LfoDelay(lfo_idx) => LFO_IDS_START + lfo_idx * LFO_IDS_STRIDE + 3,
+            PolyModTarget::LfoFade(lfo_idx) => LFO_IDS_START + lfo_idx * LFO_IDS_STRIDE + 4,
+            PolyModTarget::LfoLevelDepth(lfo_idx, op_idx) => {
+                LFO_IDS_START + lfo_idx * LFO_IDS_STRIDE + 5 + op_idx
+            }
+            PolyModTarget::ModSlotDepth(slot_idx) => MOD_SLOT_IDS_START + slot_idx,
+            PolyModTarget::Fine(op_idx) => OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE,
+            PolyModTarget::Detune(op_idx) => OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 1,
+            PolyModTarget::FixedFrequency(op_idx) => {
+                OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 2
+            }
+            PolyModTarget::Level(op_idx) => OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 3,
+            PolyModTarget::Feedback(op_idx) => {
+                OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 4
+            }
+            PolyModTarget::VelocitySensitivity(op_idx) => {
+                OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 5
+            }
+            PolyModTarget::LeftDepth(op_idx) => {
+                OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 6
+            }
+            PolyModTarget::RightDepth(op_idx) => {
+                OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 7
+            }
+            PolyModTarget::RateScaling(op_idx) => {
+                OPERATOR_IDS_START + op_idx * OPERATOR_IDS_STRIDE + 8
+            }
+            PolyModTarget::OperatorEnvelope(op_idx, target) => {
+                OPERATOR_IDS_START
+                    + op_idx * OPERATOR_IDS_STRIDE
+                    + OPERATOR_ENVELOPE_IDS_OFFSET
+                    + target.index()
+            }
+            PolyModTarget::Modulation(op_idx, modulator_idx) => {
+                OPERATOR_IDS_START
+                    + op_idx * OPERATOR_IDS_STRIDE
+                    + OPERATOR_MODULATION_IDS_OFFSET
+                    + modulator_idx
+            }
+        };
+
+        id as u32
+    }
+
+    /// The parameter belonging to a poly modulation ID, if there is one. This is the inverse of
+    /// [`id()`][Self::id()].
+    pub fn from_id(id: u32) -> Option<Self> {
+        let id = id as usize;
+        let target = match id {
+            0 => PolyModTarget::Gain,
+            1 => PolyModTarget::Macro,
+            2 => PolyModTarget::PitchEnvelopeDepth,
+            _ if id < LFO_IDS_START => {
+                PolyModTarget::PitchEnvelope(EnvelopeTarget::ALL[id - PITCH_ENVELOPE_IDS_START])
+            }
+            _ if id < MOD_SLOT_IDS_START => {
+                let lfo_idx = (id - LFO_IDS_START) / LFO_IDS_STRIDE;
+                match (id - LFO_IDS_START) % LFO_IDS_STRIDE {
+                    0 => PolyModTarget::LfoPitchDepth(lfo_idx),
+                    1 => PolyModTarget::LfoGainDepth(lfo_idx),
+                    2 => PolyModTarget::LfoRate(lfo_idx),
+                    3 => PolyModTarget::LfoDelay(lfo_idx),
+                    4 => PolyModTarget::LfoFade(lfo_idx),
+                    n => PolyModTarget::LfoLevelDepth(lfo_idx, n - 5),
+                }
+            }
+            _ if id < OPERATOR_IDS_START => PolyModTarget::ModSlotDepth(id - MOD_SLOT_IDS_START),
+            _ if id < NUM_POLY_MOD_IDS => {
+                let op_idx = (id - OPERATOR_IDS_START) / OPERATOR_IDS_STRIDE;
+                match (id - OPERATOR_IDS_START) % OPERATOR_IDS_STRIDE {
+                    0 => PolyModTarget::Fine(op_idx),
+                    1 => PolyModTarget::Detune(op_idx),
+                    2 => PolyModTarget::FixedFrequency(op_idx),
+                    3 => PolyModTarget::Level(op_idx),
+                    4 => PolyModTarget::Feedback(op_idx),
+                    5 => PolyModTarget::VelocitySensitivity(op_idx),
+                    6 => PolyModTarget::LeftDepth(op_idx),
+                    7 => PolyModTarget::RightDepth(op_idx),
+                    8 => PolyModTarget::RateScaling(op_idx),
+                    n if n < OPERATOR_MODULATION_IDS_OFFSET => PolyModTarget::OperatorEnvelope(
+                        op_idx,
+                        EnvelopeTarget::ALL[n - OPERATOR_ENVELOPE_IDS_OFFSET],
+                    ),
+                    n => PolyModTarget::Modulation(op_idx, n - OPERATOR_MODULATION_IDS_OFFSET),
+                }
+            }
+            _ => return None,
+        };
+
+        Some(target)
+    }
+
+    /// The index of the operator whose velocity sensitivity or keyboard scaling this is. These are
+    /// only read when a note starts, so the voice's levels and rates need to be recomputed when
+    /// they get modulated.
+    pub fn note_on_operator(self) -> Option<usize> {
+        match self {
+            PolyModTarget::VelocitySensitivity(op_idx)
+            | PolyModTarget::LeftDepth(op_idx)
+            | PolyModTarget::RightDepth(op_idx)
+            | PolyModTarget::RateScaling(op_idx) => Some(op_idx),
+            _ => None,
+        }
+    }
+}
+
+impl EnvelopeTarget {
+    /// All targets, in the order of their IDs.
+    pub const ALL: [Self; 24] = [
+        EnvelopeTarget::Decay,
+        EnvelopeTarget::DecayPower,
+        EnvelopeTarget::Sustain,
+        EnvelopeTarget::Release,
+        EnvelopeTarget::ReleasePower,
+        EnvelopeTarget::Rate2,
+        EnvelopeTarget::Rate3,
+        EnvelopeTarget::Rate4,
+        EnvelopeTarget::Level3,
+        EnvelopeTarget::Attack,
+        EnvelopeTarget::AttackPower,
+        EnvelopeTarget::Hold,
+        EnvelopeTarget::Rate1,
+        EnvelopeTarget::Level1,
+        EnvelopeTarget::Level2,
+        EnvelopeTarget::Level4,
+        EnvelopeTarget::AttackVelocityTracking,
+        EnvelopeTarget::AttackKeyTracking,
+        EnvelopeTarget::HoldVelocityTracking,
+        EnvelopeTarget::HoldKeyTracking,
+        EnvelopeTarget::DecayVelocityTracking,
+        EnvelopeTarget::DecayKeyTracking,
+        EnvelopeTarget::ReleaseVelocityTracking,
+        EnvelopeTarget::ReleaseKeyTracking,
+    ];
+
+    const fn index(self) -> usize {
+        match self {
+            EnvelopeTarget::Decay => 0,
+            EnvelopeTarget::DecayPower => 1,
+            EnvelopeTarget::Sustain => 2,
+            EnvelopeTarget::Release => 3,
+            EnvelopeTarget::ReleasePower => 4,
+            EnvelopeTarget::Rate2 => 5,
+            EnvelopeTarget::Rate3 => 6,
+            EnvelopeTarget::Rate4 => 7,
+            EnvelopeTarget::Level3 => 8,
+            EnvelopeTarget::Attack => 9,
+            EnvelopeTarget::AttackPower => 10,
+            EnvelopeTarget::Hold => 11,
+            EnvelopeTarget::Rate1 => 12,
+            EnvelopeTarget::Level1 => 13,
+            EnvelopeTarget::Level2 => 14,
+            EnvelopeTarget::Level4 => 15,
+            EnvelopeTarget::AttackVelocityTracking => 16,
+            EnvelopeTarget::AttackKeyTracking => 17,
+            EnvelopeTarget::HoldVelocityTracking => 18,
+            EnvelopeTarget::HoldKeyTracking => 19,
+            EnvelopeTarget::DecayVelocityTracking => 20,
+            EnvelopeTarget::DecayKeyTracking => 21,
+            EnvelopeTarget::ReleaseVelocityTracking => 22,
+            EnvelopeTarget::ReleaseKeyTracking => 23,
+        }
+    }
+}
+
+/// A voice's polyphonic modulation. Every parameter the host modulates for this voice gets the
+/// normalized offset and a smoother, indexed by the parameter's poly modulation ID.
+#[derive(Debug, Clone)]
+pub struct VoicePolyMod {
+    smoothers: [Option<(f32, Smoother<f32>)>; NUM_POLY_MOD_IDS],
+    /// The IDs in `smoothers` that have a smoother, in `ids[..num_ids]`. This way only the
+    /// modulated parameters need to be visited for every block.
+    ids: [usize; NUM_POLY_MOD_IDS],
+    num_ids: usize,
+}
+
+/// The smoothed values of all parameters that can be polyphonically modulated during a single
+/// block, indexed by poly modulation ID. These are the global parameter values, unless the
+/// current voice has polyphonic modulation applied to them.
+pub struct PolyModBlock {
+    global: [[f32; MAX_BLOCK_SIZE]; NUM_POLY_MOD_IDS],
+    /// The value every element in `global` is set to for the parameters that are not smoothing.
+    /// These don't need to be rendered again until their value changes.
+    global_constant: [Option<f32>; NUM_POLY_MOD_IDS],
+    voice: [[f32; MAX_BLOCK_SIZE]; NUM_POLY_MOD_IDS],
+    /// Whether `voice` contains the values for the parameter at the index.
+    modulated: [bool; NUM_POLY_MOD_IDS],
+    /// The IDs that are set in `modulated`, in `modulated_ids[..num_modulated_ids]`.
+    modulated_ids: [usize; NUM_POLY_MOD_IDS],
+    num_modulated_ids: usize,
+}
+
+impl Default for VoicePolyMod {
+    fn default() -> Self {
+        Self {
+            smoothers: std::array::from_fn(|_| None),
+            ids: [0; NUM_POLY_MOD_IDS],
+            num_ids: 0,
+        }
+    }
+}
+
+impl VoicePolyMod {
+    /// Handle a `PolyModulation` event for `param`, the parameter belonging to `target`. If `reset`
+    /// is set, then the voice immediately uses the new value instead of smoothly moving to it.
+    pub fn modulate(
+        &mut self,
+        target: PolyModTarget,
+        param: &FloatParam,
+        normalized_offset: f32,
+        sample_rate: f32,
+        reset: bool,
+    ) {
+        // This should either create a smoother for this modulated parameter or update the
+        // existing one. Notice how this uses the parameter's unmodulated normalized value in
+        // combination with the normalized offset to create the target plain value
+        let target_plain_value = param.preview_modulated(normalized_offset);
+        let id = target.id() as usize;
+        if self.smoothers[id].is_none() {
+            self.ids[self.num_ids] = id;
+            self.num_ids += 1;
+        }
+        let (offset, smoother) =
+            self.smoothers[id].get_or_insert_with(|| (normalized_offset, param.smoothed.clone()));
+        *offset = normalized_offset;
+
+        if reset {
+            smoother.reset(target_plain_value);
+        } else {
+            smoother.set_target(sample_rate, target_plain_value);
+        }
+    }
+
+    /// Whether the host has sent polyphonic modulation for `target` to this voice.
+    pub fn is_modulated(&self, target: PolyModTarget) -> bool {
+        self.smoothers[target.id() as usize].is_some()
+    }
+
+    /// The voice's value for `param`, the parameter belonging to `target`, ignoring smoothing. This
+    /// is used for the parameters that are read outside of a block, like when a note starts.
+    pub fn value(&self, target: PolyModTarget, param: &FloatParam) -> f32 {
+        match &self.smoothers[target.id() as usize] {
+            Some((normalized_offset, _)) => param.preview_modulated(*normalized_offset),
+            None => param.value(),
+        }
+    }
+
+    /// The voice's values for the parameters in [`EnvelopeValues`], ignoring smoothing. `target`
+    /// creates the envelope's target for one of its parameters. This is used when the note starts
+    /// and when it's released.
+    pub fn envelope_values(
+        &self,
+        params: &EnvelopeParams,
+        target: impl Fn(EnvelopeTarget) -> PolyModTarget,
+    ) -> EnvelopeValues {
+        EnvelopeValues::from_fn(|envelope_target| {
+            self.value(target(envelope_target), params.param(envelope_target))
+        })
+    }
+
+    /// Handle a `MonoAutomation` event for `param`, the parameter belonging to `target`.
+    pub fn automate(
+        &mut self,
+        target: PolyModTarget,
+        param: &FloatParam,
+        normalized_value: f32,
+        sample_rate: f32,
+    ) {
+        // If the voice does not have existing polyphonic modulation, then there's nothing to do
+        // here. The global automation/monophonic modulation has already been taken care of by the
+        // framework.
+        if let Some((normalized_offset, smoother)) = &mut self.smoothers[target.id() as usize] {
+            let target_plain_value = param.preview_plain(normalized_value + *normalized_offset);
+            smoother.set_target(sample_rate, target_plain_value);
+        }
+    }
+
+    /// Remove all polyphonic modulation.
+    pub fn clear(&mut self) {
+        self.smoothers.fill(None);
+        self.num_ids = 0;
+    }
+}
+
+impl Default for PolyModBlock {
+    fn default() -> Self {
+        Self {
+            global: [[0.0; MAX_BLOCK_SIZE]; NUM_POLY_MOD_IDS],
+            global_constant: [None; NUM_POLY_MOD_IDS],
+            voice: [[0.0; MAX_BLOCK_SIZE]; NUM_POLY_MOD_IDS],
+            modulated: [false; NUM_POLY_MOD_IDS],
+            modulated_ids: [0; NUM_POLY_MOD_IDS],
+            num_modulated_ids: 0,
+        }
+    }
+}
+
+impl PolyModBlock {
+    /// Render the global parameter values for the next block. Only the parameters that are
+    /// smoothing or whose value has changed since the last block are rendered again.
+    pub fn next_block(&mut self, params: &FmSynthParams, block_len: usize) {
+        for (id, (values, constant)) in self
+            .global
+            .iter_mut()
+            .zip(self.global_constant.iter_mut())
+            .enumerate()
+        {
+            let Some(target) = PolyModTarget::from_id(id as u32) else {
+                continue;
+            };
+
+            let smoother = &params.poly_mod_param(target).smoothed;
+            if smoother.is_smoothing() {
+                smoother.next_block(values, block_len);
+                *constant = None;
+            } else {
+                let value = smoother.previous_value();
+                if *constant != Some(value) {
+                    values.fill(value);
+                    *constant = Some(value);
+                }
+            }
+        }
+        self.clear_voice();
+    }
+
+    /// Render the values of the parameters that have polyphonic modulation applied for a voice.
+    /// Until this is called again the values are used instead of the global values.
+    pub fn next_voice_block(&mut self, poly_mod: &VoicePolyMod, block_len: usize) {
+        self.clear_voice();
+        for &id in &poly_mod.ids[..poly_mod.num_ids] {
+            if let Some((_, smoother)) = &poly_mod.smoothers[id] {
+                smoother.next_block(&mut self.voice[id], block_len);
+                self.modulated[id] = true;
+            }
+        }
+        self.modulated_ids[..poly_mod.num_ids].copy_from_slice(&poly_mod.ids[..poly_mod.num_ids]);
+        self.num_modulated_ids = poly_mod.num_ids;
+    }
+
+    /// Go back to using the global values for every parameter.
+    fn clear_voice(&mut self) {
+        for &id in &self.modulated_ids[..self.num_modulated_ids] {
+            self.modulated[id] = false;
+        }
+        self.num_modulated_ids = 0;
+    }
+
+    /// The values for `target` during this block.
+    pub fn block(&self, target: PolyModTarget) -> &[f32; MAX_BLOCK_SIZE] {
+        let id = target.id() as usize;
+        if self.modulated[id] {
+            &self.voice[id]
+        } else {
+            &self.global[id]
+        }
+    }
+
+    /// The value for `target` at `value_idx`.
+    pub fn get(&self, target: PolyModTarget, value_idx: usize) -> f32 {
+        self.block(target)[value_idx]
+    }
+
+    /// The values at the start of the block for the parameters that an envelope reads again while a
+    /// voice plays. `target` creates the envelope's target for one of its parameters, like
+    /// `PolyModTarget::PitchEnvelope`.
+    pub fn envelope_values(
+        &self,
+        target: impl Fn(EnvelopeTarget) -> PolyModTarget,
+    ) -> EnvelopeValues {
+        EnvelopeValues::from_fn(|envelope_target| self.get(target(envelope_target), 0))
+    }
+
+    /// Use the current voice's rate, delay and fade in at the start of the block for the per voice
+    /// LFO at `lfo_idx`. Tempo synced LFOs keep their rate.
+    pub fn apply_lfo(&self, lfo_idx: usize, synced: bool, settings: &mut LfoSettings) {
+        if !synced {
+            settings.rate_hz = self.get(PolyModTarget::LfoRate(lfo_idx), 0);
+        }
+        settings.delay_ms = self.get(PolyModTarget::LfoDelay(lfo_idx), 0);
+        settings.fade_ms = self.get(PolyModTarget::LfoFade(lfo_idx), 0);
+    }
+
+    /// Whether the current voice has polyphonic modulation applied to any of the parameters that
+    /// determine the operator's frequency.
+    pub fn modulates_frequency(&self, op_idx: usize) -> bool {
+        [
+            PolyModTarget::Fine(op_idx),
+            PolyModTarget::Detune(op_idx),
+            PolyModTarget::FixedFrequency(op_idx),
+        ]
+        .into_iter()
+        .any(|target| self.modulated[target.id() as usize])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every target, in no particular order.
+    fn all_targets() -> Vec<PolyModTarget> {
+        let mut targets = vec![
+            PolyModTarget::Gain,
+            PolyModTarget::Macro,
+            PolyModTarget::PitchEnvelopeDepth,
+        ];
+        targets.extend(EnvelopeTarget::ALL.map(PolyModTarget::PitchEnvelope));
+        for lfo_idx in 0..NUM_LFOS {
+            targets.extend([
+                PolyModTarget::LfoPitchDepth(lfo_idx),
+                PolyModTarget::LfoGainDepth(lfo_idx),
+                PolyModTarget::LfoRate(lfo_idx),
+                PolyModTarget::LfoDelay(lfo_idx),
+                PolyModTarget::LfoFade(lfo_idx),
+            ]);
+            targets.extend(
+                (0..NUM_OPERATORS).map(|op_idx| PolyModTarget::LfoLevelDepth(lfo_idx, op_idx)),
+            );
+        }
+        targets.extend((0..NUM_MOD_SLOTS).map(PolyModTarget::ModSlotDepth));
+        for op_idx in 0..NUM_OPERATORS {
+            targets.extend([
+                PolyModTarget::Fine(op_idx),
+                PolyModTarget::Detune(op_idx),
+                PolyModTarget::FixedFrequency(op_idx),
+                PolyModTarget::Level(op_idx),
+                PolyModTarget::Feedback(op_idx),
+                PolyModTarget::VelocitySensitivity(op_idx),
+                PolyModTarget::LeftDepth(op_idx),
+                PolyModTarget::RightDepth(op_idx),
+                PolyModTarget::RateScaling(op_idx),
+            ]);
+            targets.extend(
+                EnvelopeTarget::ALL.map(|target| PolyModTarget::OperatorEnvelope(op_idx, target)),
+            );
+            targets.extend(
+                (0..NUM_OPERATORS)
+                    .map(|modulator_idx| PolyModTarget::Modulation(op_idx, modulator_idx)),
+            );
+        }
+
+        targets
+    }
+
+    #[test]
+    fn ids_round_trip() {
+        let targets = all_targets();
+        assert_eq!(targets.len(), NUM_POLY_MOD_IDS);
+
+        let mut seen = [false; NUM_POLY_MOD_IDS];
+        for target in targets {
+            let id = target.id();
+            assert!((id as usize) < NUM_POLY_MOD_IDS, "{target:?} has ID {id}");
+            assert!(!seen[id as usize], "{target:?} shares ID {id}");
+            seen[id as usize] = true;
+
+            assert_eq!(PolyModTarget::from_id(id), Some(target));
+        }
+        assert_eq!(PolyModTarget::from_id(NUM_POLY_MOD_IDS as u32), None);
+    }
+
+    #[test]
+    fn voice_blocks_only_replace_the_modulated_values() {
+        let params = FmSynthParams::default();
+        let mut block = PolyModBlock::default();
+        block.next_block(&params, MAX_BLOCK_SIZE);
+
+        let target = PolyModTarget::OperatorEnvelope(2, EnvelopeTarget::Attack);
+        let param = params.poly_mod_param(target);
+        let global_value = block.get(target, 0);
+        let global_gain = block.get(PolyModTarget::Gain, 0);
+        let mut voice = VoicePolyMod::default();
+        voice.modulate(target, param, 0.25, 44_100.0, true);
+        block.next_voice_block(&voice, MAX_BLOCK_SIZE);
+        assert_eq!(
+            block.get(target, MAX_BLOCK_SIZE - 1),
+            param.preview_modulated(0.25)
+        );
+        assert_eq!(block.get(PolyModTarget::Gain, 0), global_gain);
+
+        // The next voice doesn't have any modulation
+        block.next_voice_block(&VoicePolyMod::default(), MAX_BLOCK_SIZE);
+        assert_eq!(block.get(target, 0), global_value);
+    }
+}
@@ -0,0 +1,56 @@
+use nih_plug::prelude::*;
+
+/// The tempo used for tempo synced timings when the host doesn't report one.
+pub const DEFAULT_TEMPO: f64 = 120.0;
+
+/// A note length for tempo synced timings, assuming four beats per bar.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum NoteDivision {
+    #[name = "1/16"]
+    Sixteenth,
+    #[name = "1/8"]
+    Eighth,
+    #[name = "1/4"]
+    Quarter,
+    #[name = "1/2"]
+    Half,
+    #[name = "1 Bar"]
+    Bar,
+    #[name = "2 Bars"]
+    TwoBars,
+    #[name = "4 Bars"]
+    FourBars,
+}
+
+impl NoteDivision {
+    /// The division's length in beats.
+    pub fn beats(self) -> f64 {
+        match self {
+            NoteDivision::Sixteenth => 0.25,
+            NoteDivision::Eighth => 0.5,
+            NoteDivision::Quarter => 1.0,
+            NoteDivision::Half => 2.0,
+            NoteDivision::Bar => 4.0,
+            NoteDivision::TwoBars => 8.0,
+            NoteDivision::FourBars => 16.0,
+        }
+    }
+
+    /// The division's length in milliseconds at `tempo` beats per minute.
+    pub fn duration_ms(self, tempo: f64) -> f32 {
+        (self.beats() * 60_000.0 / tempo) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_follow_the_tempo() {
+        assert_eq!(NoteDivision::Quarter.duration_ms(DEFAULT_TEMPO), 500.0);
+        assert_eq!(NoteDivision::Bar.duration_ms(DEFAULT_TEMPO), 2000.0);
+        assert_eq!(NoteDivision::Sixteenth.duration_ms(60.0), 250.0);
+        assert_eq!(NoteDivision::FourBars.duration_ms(240.0), 4000.0);
+    }
+}
@@ -0,0 +1,62 @@
+use nih_plug::prelude::*;
+
+/// The range in decibels of the exponential velocity curve.
+const EXPONENTIAL_RANGE_DB: f32 = 40.0;
+
+/// How a note's velocity is mapped to a gain multiplier.
+#[derive(Enum, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VelocityCurve {
+    #[name = "Linear"]
+    Linear,
+    #[name = "Square Root"]
+    SquareRoot,
+    /// The velocity is mapped linearly to decibels, so every step in velocity changes the level by
+    /// the same number of decibels.
+    #[name = "Exponential"]
+    Exponential,
+    /// The velocity is ignored.
+    #[name = "Fixed"]
+    Fixed,
+}
+
+impl VelocityCurve {
+    /// Map a velocity in `[0, 1]` to a gain multiplier in `[0, 1]`.
+    pub fn apply(self, velocity: f32) -> f32 {
+        match self {
+            VelocityCurve::Linear => velocity,
+            VelocityCurve::SquareRoot => velocity.sqrt(),
+            VelocityCurve::Exponential => util::db_to_gain((velocity - 1.0) * EXPONENTIAL_RANGE_DB),
+            VelocityCurve::Fixed => 1.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curves_map_full_velocity_to_unity_gain() {
+        for curve in [
+            VelocityCurve::Linear,
+            VelocityCurve::SquareRoot,
+            VelocityCurve::Exponential,
+            VelocityCurve::Fixed,
+        ] {
+            assert_eq!(curve.apply(1.0), 1.0);
+        }
+    }
+
+    #[test]
+    fn curves_shape_lower_velocities() {
+        assert_eq!(VelocityCurve::Linear.apply(0.25), 0.25);
+        assert_eq!(VelocityCurve::SquareRoot.apply(0.25), 0.5);
+        assert_eq!(VelocityCurve::Fixed.apply(0.0), 1.0);
+
+        // Every step in velocity changes the level by the same number of decibels
+        let exponential_db =
+            |velocity| util::gain_to_db(VelocityCurve::Exponential.apply(velocity));
+        assert!((exponential_db(0.0) + EXPONENTIAL_RANGE_DB).abs() < 1e-3);
+        assert!((exponential_db(0.5) + EXPONENTIAL_RANGE_DB / 2.0).abs() < 1e-3);
+    }
+}